@@ -0,0 +1,65 @@
+// Compare the rope used for the buffer with a plain vector of lines.
+//
+// Run with `cargo bench --bench storage`.
+
+use ned::{Change, Rope, Storage};
+use std::time::{Duration, Instant};
+
+const LINES: usize = 1_000_000;
+const EDITS: usize = 1_000;
+
+fn lines(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!("line {}", i)).collect()
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn bench(name: &str, lines: &mut dyn Storage) {
+    let append = time(|| {
+        for i in 0..EDITS {
+            let n = lines.len();
+            lines.splice(n, 0, vec![i.to_string()]);
+        }
+    });
+    let insert = time(|| {
+        for i in 0..EDITS {
+            lines.splice(0, 0, vec![i.to_string()]);
+        }
+    });
+    let delete = time(|| {
+        for _ in 0..EDITS {
+            lines.splice(0, 1, vec![]);
+        }
+    });
+    let moves = time(|| {
+        for i in 0..EDITS {
+            let old = lines.splice(i, 10, vec![]);
+            let n = lines.len();
+            lines.splice(n / 2, 0, old);
+        }
+    });
+    let mut changes = Vec::with_capacity(EDITS);
+    for i in 0..EDITS {
+        let old = lines.splice(i, 1, vec![]);
+        changes.push(Change { at: i, old, new: vec![] });
+    }
+    let undo = time(|| {
+        for change in changes.iter().rev() {
+            change.revert(lines);
+        }
+    });
+    println!(
+        "{:<6} append {:>10.2?}  insert {:>10.2?}  delete {:>10.2?}  move {:>10.2?}  undo {:>10.2?}",
+        name, append, insert, delete, moves, undo
+    );
+}
+
+fn main() {
+    println!("{} lines, {} edits", LINES, EDITS);
+    bench("vec", &mut lines(LINES));
+    bench("rope", &mut Rope::from(lines(LINES)));
+}
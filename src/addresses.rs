@@ -1,85 +1,131 @@
 use crate::editor::*;
 use crate::commands::*;
+use crate::utils::*;
 use regex::Regex;
 
-pub trait Addresses {
-    fn parse_addr(&self, addr: &str) -> Option<usize>;
-    fn parse_addr_1(&self, addr: &str, sep: &str) -> Option<usize>;
-    fn parse_addr_2(&self, addr: &str, sep: &str) -> Option<usize>;
-    fn search_forward_regex(&self, pattern: &str) -> Option<usize>;
+// A single address, like the ones of RE_ADDRS, which must match the whole
+// input given to parse_addr.
+static RE_ADDR: &str = r"^(?:/(?:[^\\/]|\\.)*/I?|'[a-z]|[.$]|[-+]?[0-9]*)$";
+
+pub(crate) trait Addresses {
+    fn parse_addr(&self, addr: &str) -> Result<usize, Error>;
+    fn parse_addr_1(&self, addr: &str, sep: &str) -> Result<Option<usize>, Error>;
+    fn parse_addr_2(&self, addr: &str, sep: &str) -> Result<Option<usize>, Error>;
+    fn search_forward_regex(&self, pattern: &str, case_insensitive: bool) -> Result<usize, Error>;
+    fn search_forward_regex_multiline(&self, re: &Regex) -> Result<usize, Error>;
     fn is_range_ok(&self, cl: CommandLine) -> bool;
 }
 
 impl Addresses for Editor {
-    fn parse_addr(&self, addr: &str) -> Option<usize> {
+    fn parse_addr(&self, addr: &str) -> Result<usize, Error> {
+        if addr.is_empty() || !Regex::new(RE_ADDR).unwrap().is_match(addr) {
+            return Err(Error::InvalidAddress);
+        }
         match &addr[0..1] {
-            "." => Some(self.addr),
-            "$" => Some(self.lines.len()),
-            "/" => self.search_forward_regex(&addr[1..addr.len() - 1]),
-            "+" => Some(self.addr + addr[1..].parse::<usize>().unwrap()),
-            "-" => Some(self.addr - addr[1..].parse::<usize>().unwrap()),
-            _   => Some(addr.parse::<usize>().unwrap()),
+            "." => Ok(self.addr),
+            "'" => addr[1..].chars().next().and_then(|c| self.marks.get(&c).cloned()).ok_or(Error::InvalidAddress),
+            "$" => Ok(self.lines.len()),
+            "/" => {
+                if let Some(pattern) = addr.strip_suffix("/I") {
+                    self.search_forward_regex(&pattern[1..], true)
+                } else {
+                    self.search_forward_regex(&addr[1..addr.len() - 1], false)
+                }
+            },
+            "+" => self.addr.checked_add(parse_offset(&addr[1..])?).ok_or(Error::InvalidAddress),
+            "-" => self.addr.checked_sub(parse_offset(&addr[1..])?).ok_or(Error::InvalidAddress),
+            _   => addr.parse::<usize>().map_err(|_| Error::InvalidNumber),
         }
     }
 
-    fn parse_addr_1(&self, addr: &str, sep: &str) -> Option<usize> {
-        if addr == "" {
-            if sep == "" { None } else { Some(1) }
+    fn parse_addr_1(&self, addr: &str, sep: &str) -> Result<Option<usize>, Error> {
+        if addr.is_empty() {
+            Ok(if sep.is_empty() { None } else { Some(1) })
         } else {
-            self.parse_addr(addr)
+            self.parse_addr(addr).map(Some)
         }
     }
 
-    fn parse_addr_2(&self, addr: &str, sep: &str) -> Option<usize> {
-        if addr == "" {
-            if sep == "" { None } else { Some(self.lines.len()) }
+    fn parse_addr_2(&self, addr: &str, sep: &str) -> Result<Option<usize>, Error> {
+        if addr.is_empty() {
+            Ok(if sep.is_empty() { None } else { Some(self.lines.len()) })
         } else {
-            self.parse_addr(addr)
+            self.parse_addr(addr).map(Some)
         }
     }
 
-    fn search_forward_regex(&self, pattern: &str) -> Option<usize> {
-        let re = Regex::new(pattern).unwrap();
+    fn search_forward_regex(&self, pattern: &str, case_insensitive: bool) -> Result<usize, Error> {
+        let re = build_regex(pattern, case_insensitive)?;
+        if pattern.contains("\\n") {
+            return self.search_forward_regex_multiline(&re);
+        }
         let n = self.lines.len();
         let mut i = self.addr;
-        loop {
-            if i == n { // Wrap around to the beginning
+        for _ in 0..n {
+            if i >= n { // Wrap around to the beginning
                 i = 1;
             } else {
                 i += 1;
             }
-            if re.is_match(&self.lines[i - 1]) { // Return first match
-                return Some(i);
-            } else if i == self.addr { // No matches in all the buffer
-                return None;
+            if re.is_match(&self.lines.get(i - 1)) { // Return first match
+                return Ok(i);
             }
         }
+        Err(Error::NoMatch) // No matches in all the buffer
+    }
+
+    // A pattern containing a newline is matched against the whole buffer so
+    // that it can span multiple lines, the address being the line where the
+    // match starts.
+    fn search_forward_regex_multiline(&self, re: &Regex) -> Result<usize, Error> {
+        let mut offsets = Vec::with_capacity(self.lines.len());
+        let mut offset = 0;
+        for line in self.lines.iter() {
+            offsets.push(offset);
+            offset += line.len() + 1;
+        }
+        let text = self.lines.join("\n");
+        let start = if self.addr < offsets.len() { offsets[self.addr] } else { 0 };
+        let m = re.find_at(&text, start).or_else(|| re.find(&text)); // Wrap around
+        match m.map(|m| offsets.binary_search(&m.start())) {
+            Some(Ok(i)) => Ok(i + 1),
+            Some(Err(i)) => Ok(i),
+            None => Err(Error::NoMatch)
+        }
     }
 
-    fn is_range_ok(&self, _cl: CommandLine) -> bool {
-        // FIXME: Validate range
-        /*
+    fn is_range_ok(&self, cl: CommandLine) -> bool {
+        let addr_1 = cl.addr_1.unwrap_or(self.addr);
+        let addr_2 = cl.addr_2.unwrap_or(addr_1);
         if addr_1 > addr_2 || addr_2 > self.lines.len() {
-            if addr_1 != 0 || cmd != "a" || !cmd.to_lowercase().ends_with("q") {
-                return false;
-            }
+            return false;
+        }
+        // Commands working on the addressed lines need at least one of them
+        match cl.cmd.as_str() {
+            "" | "c" | "d" | "g" | "k" | "m" | "n" | "p" | "s" | "t" | "y" => addr_1 > 0,
+            _ => true
         }
-        */
-        true
+    }
+}
+
+// An offset without a number is the same as an offset of 1
+fn parse_offset(offset: &str) -> Result<usize, Error> {
+    if offset.is_empty() {
+        Ok(1)
+    } else {
+        offset.parse::<usize>().map_err(|_| Error::InvalidNumber)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::commands::*;
+    use crate::filesystem::tests::*;
 
-    static TEST_FILE: &str = "LICENSE";
-    static TEST_FILE_LENGTH: usize = 21;
 
     #[test]
     fn test_parse_addr() {
-        let mut ed = Editor::new();
+        let (mut ed, _) = test_editor();
         let cl = CommandLine {
             addr_1: None,
             addr_2: None,
@@ -89,11 +135,21 @@ mod tests {
         };
         ed.edit_command(cl).ok();
         ed.addr = 10;
-        assert_eq!(ed.parse_addr("2"), Some(2));
-        assert_eq!(ed.parse_addr("."), Some(10));
-        assert_eq!(ed.parse_addr("$"), Some(TEST_FILE_LENGTH));
-        assert_eq!(ed.parse_addr("+2"), Some(12));
-        assert_eq!(ed.parse_addr("-2"), Some(8));
-        assert_eq!(ed.parse_addr("/free/"), Some(5));
+        assert_eq!(ed.parse_addr("2"), Ok(2));
+        assert_eq!(ed.parse_addr("."), Ok(10));
+        assert_eq!(ed.parse_addr("$"), Ok(TEST_FILE_LENGTH));
+        assert_eq!(ed.parse_addr("+2"), Ok(12));
+        assert_eq!(ed.parse_addr("-2"), Ok(8));
+        assert_eq!(ed.parse_addr("+"), Ok(11));
+        assert_eq!(ed.parse_addr("-20"), Err(Error::InvalidAddress));
+        assert_eq!(ed.parse_addr("99999999999999999999"), Err(Error::InvalidNumber));
+        assert_eq!(ed.parse_addr("/free/"), Ok(5));
+        assert_eq!(ed.parse_addr("/FREE/"), Err(Error::NoMatch));
+        assert_eq!(ed.parse_addr("/FREE/I"), Ok(5));
+        assert_eq!(ed.parse_addr("/copy\\nof/"), Ok(5));
+        assert_eq!(ed.parse_addr("/(/"), Err(Error::InvalidRegex));
+        for addr in &["", "/", "/x", "é", "2x", "'"] {
+            assert_eq!(ed.parse_addr(addr), Err(Error::InvalidAddress));
+        }
     }
 }
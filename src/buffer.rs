@@ -0,0 +1,43 @@
+use crate::filesystem::Stamp;
+use crate::format::Format;
+use crate::storage::{Rope, Storage};
+use crate::undo::UndoTree;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+// A file opened in another buffer than the current one, which is kept in
+// the editor itself to be edited.
+#[derive(Debug)]
+pub struct Buffer {
+    pub(crate) dirty: bool,
+    pub(crate) read_only: bool,
+    pub(crate) journaled: bool,
+    pub(crate) filename: Option<String>,
+    pub(crate) stamp: Option<Stamp>,
+    pub(crate) format: Format,
+    pub(crate) addr: usize,
+    pub(crate) lines: Box<dyn Storage>,
+    pub(crate) marks: BTreeMap<char, usize>,
+    pub(crate) history: Vec<String>,
+    pub(crate) undo_tree: UndoTree,
+    pub(crate) lock: Option<PathBuf>,
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Buffer {
+            dirty: false,
+            read_only: false,
+            journaled: true,
+            filename: None,
+            stamp: None,
+            format: Format::default(),
+            addr: 0,
+            lines: Box::new(Rope::new()),
+            marks: BTreeMap::new(),
+            history: Vec::new(),
+            undo_tree: UndoTree::new(0),
+            lock: None,
+        }
+    }
+}
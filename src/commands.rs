@@ -1,8 +1,61 @@
 use crate::utils::*;
+use crate::buffer::*;
 use crate::editor::*;
-use std::fs;
+use crate::format::*;
+use crate::storage::*;
+use crate::undo::*;
+use std::path::Path;
 use std::process;
-use regex::Regex;
+
+static HELP: [(&str, &str, &str); 47] = [
+    ("a", "(.)a", "Append text after the addressed line"),
+    ("b", "(.)b", "Insert text before the addressed line"),
+    ("i", "(.)i", "Insert text before the addressed line"),
+    ("c", "(.,.)c", "Change the addressed lines"),
+    ("d", "(.,.)d [x]", "Delete the addressed lines, into register x if given"),
+    ("m", "(.,.)m [buffer:]addr", "Move the addressed lines after addr"),
+    ("t", "(.,.)t [buffer:]addr", "Copy the addressed lines after addr"),
+    ("y", "(.,.)y [x]", "Yank the addressed lines into register x"),
+    ("pu", "(.)pu [x]", "Put the lines of register x after the addressed line"),
+    ("registers", "registers", "List the registers"),
+    ("rec", "rec [x]", "Record the commands into register x, or stop recording"),
+    ("@", "(.)@x [count]", "Replay the commands of register x from the addressed line"),
+    ("wmacros", "wmacros [file]", "Write the registers to file to keep the macros"),
+    ("rmacros", "rmacros [file]", "Read the registers written by wmacros"),
+    ("k", "(.)kx", "Mark the addressed line with x, to be addressed by 'x"),
+    ("e", "e file", "Edit file, discarding the changes of a previous session with e!"),
+    ("recover", "recover file", "Edit file with the changes of a previous session"),
+    ("open", "open file", "Edit file in a new buffer, read-only with open!"),
+    ("ls", "ls", "List the buffers"),
+    ("buffer", "buffer [number]", "Switch to a buffer, or print the current one"),
+    ("f", "f [file]", "Set or print the file name"),
+    ("w", "w [file]", "Write the buffer to file, even if it changed on disk with w!"),
+    ("reload", "reload", "Read the file again, discarding changes with reload!"),
+    ("diff", "diff", "Show the differences between the buffer and the file"),
+    ("r", "r file", "Read file at the end of the buffer"),
+    ("r!", "r!command", "Read the output of a shell command"),
+    ("encoding", "encoding [utf8|latin1]", "Set or print the encoding of the file"),
+    ("ending", "ending [lf|crlf]", "Set or print the line ending of the file"),
+    ("eol", "eol [on|off]", "Set or print if the file ends with a newline"),
+    ("", "(.+1)", "Go to the addressed line and print it"),
+    ("p", "(.,.)p", "Print the addressed lines"),
+    ("n", "(.,.)n", "Print the addressed lines with their numbers"),
+    ("g", "(.,.)g/re/[I] [p|n|d]", "Run a command on the lines matching re"),
+    ("s", "(.,.)s/re/replacement/[g|n][I]", "Substitute re in the addressed lines"),
+    ("u", "u", "Undo the last change, or the last undo"),
+    ("undo", "undo [state]", "Undo the last change, or go to a state of undolist"),
+    ("redo", "redo", "Redo the last undone change"),
+    ("undolist", "undolist", "List the states of the undo tree"),
+    ("earlier", "earlier [count]", "Go to an earlier state of the undo tree"),
+    ("later", "later [count]", "Go to a later state of the undo tree"),
+    ("h", "h", "Explain the last error"),
+    ("H", "H", "Toggle the explanation of errors"),
+    ("help", "help [command]", "List the commands, or describe one"),
+    ("P", "P", "Toggle the prompt"),
+    ("set", "set [option[=value]]", "Set an option, or print one or all of them"),
+    ("q", "q", "Quit, or quit without checking with q!"),
+    ("x", "x", "Write the buffer and quit"),
+];
 
 #[derive(Clone, Debug)]
 pub struct CommandLine {
@@ -14,42 +67,73 @@ pub struct CommandLine {
 }
 
 impl CommandLine {
-    pub fn is_edit(&self) -> bool {
-        self.cmd.as_str() == "e"
-    }
-
-    pub fn is_undo(&self) -> bool {
-        self.cmd.as_str() == "u"
-    }
-
+    #[allow(clippy::match_like_matches_macro)]
     pub fn is_undoable(&self) -> bool {
         match self.cmd.as_str() {
-            "a" | "b" | "i" | "c" | "d" | "f" | "r" | "s" => true,
+            "a" | "b" | "i" | "c" | "d" | "f" | "g" | "r" | "s" => true,
             _ => false
         }
     }
+
+    #[allow(clippy::wrong_self_convention)]
     pub fn into_cmd(&self, cmd: &str) -> CommandLine {
         let mut cl = self.clone();
         cl.cmd = cmd.to_string();
         cl
     }
+
+    pub fn with_params(&self, params: &[String]) -> CommandLine {
+        let mut cl = self.clone();
+        cl.params = params.to_vec();
+        cl
+    }
 }
 
-pub trait Commands {
+pub(crate) trait Commands {
     fn command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn append_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn insert_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn change_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn delete_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn move_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn copy_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn yank_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn put_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn registers_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn record_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn macro_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn write_macros_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn read_macros_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn mark_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn edit_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn recover_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn open_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn list_buffers_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn buffer_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn filename_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn write_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn read_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn reload_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn diff_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn encoding_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn ending_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn eol_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn null_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn print_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn number_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn global_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn substitute_command(&mut self, cl: CommandLine) -> Result<State, Error>;
-    fn quit_command(&self, cl: CommandLine) -> Result<State, Error>;
+    fn undo_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn redo_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn undolist_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn earlier_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn later_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn help_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn help_mode_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn help_reference_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn prompt_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn set_command(&mut self, cl: CommandLine) -> Result<State, Error>;
+    fn quit_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn write_and_quit_command(&mut self, cl: CommandLine) -> Result<State, Error>;
     fn invalid_command(&self) -> Result<State, Error>;
 }
@@ -62,14 +146,45 @@ impl Commands for Editor {
             "i" => self.insert_command(cl), // [i]nsert before
             "c" => self.change_command(cl), // [d] + [i]
             "d" => self.delete_command(cl),
+            "m" => self.move_command(cl),
+            "t" => self.copy_command(cl),
+            "y" => self.yank_command(cl),
+            "pu" => self.put_command(cl),
+            "registers" => self.registers_command(cl),
+            "rec" => self.record_command(cl),
+            "@" => self.macro_command(cl),
+            "wmacros" => self.write_macros_command(cl),
+            "rmacros" => self.read_macros_command(cl),
+            "k" => self.mark_command(cl),
             "e" => self.edit_command(cl),
+            "recover" => self.recover_command(cl),
+            "open" => self.open_command(cl),
+            "ls" => self.list_buffers_command(cl),
+            "buffer" => self.buffer_command(cl),
             "f" => self.filename_command(cl),
             "w" => self.write_command(cl),
             "r" => self.read_command(cl),
+            "reload" => self.reload_command(cl),
+            "diff" => self.diff_command(cl),
+            "encoding" => self.encoding_command(cl),
+            "ending" => self.ending_command(cl),
+            "eol" => self.eol_command(cl),
+            ""  => self.null_command(cl),
             "p" => self.print_command(cl),
             "n" => self.number_command(cl),
             "g" => self.global_command(cl),
             "s" => self.substitute_command(cl),
+            "u" => self.undo_command(cl),
+            "undo" => self.undo_command(cl),
+            "redo" => self.redo_command(cl),
+            "undolist" => self.undolist_command(cl),
+            "earlier" => self.earlier_command(cl),
+            "later" => self.later_command(cl),
+            "h" => self.help_command(cl),
+            "H" => self.help_mode_command(cl),
+            "help" => self.help_reference_command(cl),
+            "P" => self.prompt_command(cl),
+            "set" => self.set_command(cl),
             "q" => self.quit_command(cl),
             "x" => self.write_and_quit_command(cl), // [w] + [q]
             _   => self.invalid_command()
@@ -97,41 +212,309 @@ impl Commands for Editor {
     }
 
     fn delete_command(&mut self, cl: CommandLine) -> Result<State, Error> {
-        self.lines.drain(cl.addr_1.unwrap() - 1 .. cl.addr_2.unwrap());
-        self.addr = cl.addr_1.unwrap();
+        let register = self.parse_register(&cl.params)?;
+        let n = cl.addr_2.unwrap() + 1 - cl.addr_1.unwrap();
+        let lines = (cl.addr_1.unwrap() .. cl.addr_2.unwrap() + 1).map(|i| self.lines.get(i - 1).into_owned()).collect();
+        self.set_register(register, lines);
+        self.splice(cl.addr_1.unwrap() - 1, n, vec![]);
+        self.addr = cl.addr_1.unwrap().min(self.lines.len());
         self.dirty = true;
         Ok(State::Running)
     }
 
+    // The lines can be moved or copied to another buffer, where they are
+    // journaled as appended lines to be recovered without this buffer.
+    fn move_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let (addr_1, addr_2) = (cl.addr_1.unwrap(), cl.addr_2.unwrap());
+        let (buffer, dest) = self.parse_target(&cl.params)?;
+        let n = addr_2 + 1 - addr_1;
+        if buffer == self.buffer {
+            if dest >= addr_1 && dest < addr_2 {
+                return Err(Error::InvalidAddress);
+            }
+            let lines = (addr_1 .. addr_2 + 1).map(|i| self.lines.get(i - 1).into_owned()).collect();
+            self.splice(addr_1 - 1, n, vec![]);
+            let at = if dest >= addr_2 { dest - n } else { dest };
+            self.splice(at, 0, lines);
+            self.addr = at + n;
+            self.log(&format!("{},{}m{}", addr_1, addr_2, dest));
+        } else {
+            self.copy_to_buffer(buffer, addr_1, addr_2, dest)?;
+            self.splice(addr_1 - 1, n, vec![]);
+            self.addr = addr_1.min(self.lines.len());
+            self.log(&format!("{},{}d", addr_1, addr_2));
+        }
+        self.dirty = true;
+        Ok(State::Running)
+    }
+
+    fn copy_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let (addr_1, addr_2) = (cl.addr_1.unwrap(), cl.addr_2.unwrap());
+        let (buffer, dest) = self.parse_target(&cl.params)?;
+        if buffer == self.buffer {
+            let lines = (addr_1 .. addr_2 + 1).map(|i| self.lines.get(i - 1).into_owned()).collect();
+            self.splice(dest, 0, lines);
+            self.addr = dest + addr_2 + 1 - addr_1;
+            self.log(&format!("{},{}t{}", addr_1, addr_2, dest));
+            self.dirty = true;
+        } else {
+            self.copy_to_buffer(buffer, addr_1, addr_2, dest)?;
+        }
+        Ok(State::Running)
+    }
+
+    fn yank_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let register = self.parse_register(&cl.params)?;
+        let lines = (cl.addr_1.unwrap() .. cl.addr_2.unwrap() + 1).map(|i| self.lines.get(i - 1).into_owned()).collect();
+        self.set_register(register, lines);
+        Ok(State::Running)
+    }
+
+    // The lines are journaled as appended to be recovered without registers
+    fn put_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let name = self.parse_register(&cl.params)?.map_or('"', |c| c.to_ascii_lowercase());
+        match self.registers.get(&name) {
+            Some(lines) if !lines.is_empty() => {
+                self.append_lines(cl.addr_1.unwrap(), lines.clone());
+                Ok(State::Running)
+            },
+            _ => Err(Error::EmptyRegister)
+        }
+    }
+
+    fn registers_command(&mut self, _cl: CommandLine) -> Result<State, Error> {
+        let lines: Vec<String> = self.registers.iter().map(|(name, lines)| {
+            let first = lines.first().map_or("", |line| line.as_str());
+            format!("\"{} {} ({} lines)", name, first, lines.len())
+        }).collect();
+        for line in lines {
+            self.print(line);
+        }
+        Ok(State::Running)
+    }
+
+    // Like q in vi, but an uppercase name appends to the register
+    fn record_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        self.recording = self.parse_register(&cl.params)?.map(|name| {
+            if name.is_ascii_lowercase() {
+                self.registers.insert(name, Vec::new());
+            }
+            name.to_ascii_lowercase()
+        });
+        Ok(State::Running)
+    }
+
+    // The commands of a macro go through exec like typed ones, and are
+    // committed to the undo tree as a single change. The replay stops at
+    // the first error.
+    fn macro_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let name = self.parse_register(&cl.params)?.ok_or(Error::InvalidCommand)?.to_ascii_lowercase();
+        let count = match cl.params.as_slice() {
+            [_, n, ..] => n.parse::<usize>().map_err(|_| Error::InvalidNumber)?,
+            _ => 1
+        };
+        let inputs = match self.registers.get(&name) {
+            Some(lines) if !lines.is_empty() => lines.clone(),
+            _ => return Err(Error::EmptyRegister)
+        };
+        if self.macro_depth >= MACRO_DEPTH {
+            return Err(Error::InvalidCommand); // A macro replaying itself
+        }
+        let undo_addr = self.undo_addr;
+        self.addr = cl.addr_1.unwrap();
+        self.macro_depth += 1;
+        let mut res = Ok(State::Running);
+        'replay: for _ in 0..count {
+            for input in &inputs {
+                res = self.exec(input);
+                if res != Ok(State::Running) {
+                    break 'replay;
+                }
+            }
+        }
+        self.macro_depth -= 1;
+        self.undo_addr = undo_addr;
+        res
+    }
+
+    // Each register is written as "@x n" followed by its n lines
+    fn write_macros_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let path = match cl.params.first() {
+            Some(file) => Path::new(file).to_path_buf(),
+            None => {
+                self.fs.create_dir_all(&self.state_dir).map_err(|e| Error::Io(e.kind()))?;
+                self.state_dir.join("macros")
+            }
+        };
+        let mut lines = Vec::new();
+        for (name, register) in self.registers.iter().filter(|(name, _)| name.is_ascii_lowercase()) {
+            lines.push(format!("@{} {}", name, register.len()));
+            lines.extend_from_slice(register);
+        }
+        let data = Format::default().encode(&lines)?;
+        self.fs.write(&path, &data).map_err(|e| Error::Io(e.kind()))?;
+        Ok(State::Running)
+    }
+
+    fn read_macros_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let path = match cl.params.first() {
+            Some(file) => Path::new(file).to_path_buf(),
+            None => self.state_dir.join("macros")
+        };
+        let lines = read_lines(self.fs.as_ref(), &path)?;
+        let invalid = Error::Io(std::io::ErrorKind::InvalidData);
+        let mut registers = Vec::new();
+        let mut i = 0;
+        while i < lines.len() {
+            let (name, n) = match lines[i].split_once(' ') {
+                Some((name, n)) if name.len() == 2 && name.starts_with('@') => (name, n),
+                _ => return Err(invalid)
+            };
+            let name = name.chars().nth(1).filter(|c| c.is_ascii_lowercase()).ok_or(invalid.clone())?;
+            let n = n.parse::<usize>().map_err(|_| invalid.clone())?;
+            if i + 1 + n > lines.len() {
+                return Err(invalid);
+            }
+            registers.push((name, lines[i + 1 .. i + 1 + n].to_vec()));
+            i += 1 + n;
+        }
+        self.registers.extend(registers);
+        Ok(State::Running)
+    }
+
+    fn mark_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let mark = match cl.params.first().map(|p| p.as_bytes()) {
+            Some(&[c]) if c.is_ascii_lowercase() => c as char,
+            _ => return Err(Error::InvalidCommand)
+        };
+        self.marks.insert(mark, cl.addr_1.unwrap());
+        Ok(State::Running)
+    }
+
     fn edit_command(&mut self, cl: CommandLine) -> Result<State, Error> {
-        if cl.params.len() == 0 {
+        if cl.params.is_empty() {
             return Err(Error::NoFilename);
         }
+        let filename = cl.params[0].clone();
+
+        // A file has a single buffer, sharing its lock and its journal
+        if let Some(i) = self.find_buffer(&filename).filter(|&i| i != self.buffer) {
+            self.switch_buffer(i);
+            return Ok(State::Running);
+        }
+        let lock = self.try_lock(&filename)?;
+
+        // The journal of a session that didn't quit properly is only
+        // discarded with e!, and the ones of this session are kept.
+        let is_open = self.find_buffer(&filename).is_some();
+        if !self.read_only && !cl.flag && !is_open && self.has_journal(&filename) {
+            self.drop_lock(lock);
+            return Err(Error::Recoverable);
+        }
         self.rm_log();
         self.rm_tmp();
-        let filename = cl.params[0].clone();
 
-        match read_lines(&filename) {
+        match self.read_storage(&filename) {
             Err(error) => {
-                self.mk_tmp();
-                return Err(error);
+                self.drop_lock(lock);
+                self.mk_tmp().ok();
+                Err(error)
             },
-            Ok(lines) => {
+            Ok((lines, format)) => {
+                self.set_lock(lock);
                 self.filename = Some(filename.clone());
+                if self.has_journal(&filename) {
+                    self.rm_log(); // Discard the changes of a previous session with e!
+                }
                 self.lines = lines;
+                self.format = format;
+                self.marks.clear();
                 self.addr = self.lines.len();
                 self.dirty = false;
-                self.mk_tmp();
+                self.history = Vec::new();
+                self.changes = Vec::new();
+                self.undo_tree = UndoTree::new(self.addr);
+                self.stamp_file();
+                self.start_journal();
                 Ok(State::Running)
             }
         }
     }
 
+    fn recover_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let filename = cl.params.first().ok_or(Error::NoFilename)?.clone();
+        if !self.has_journal(&filename) {
+            return Err(Error::CannotOpen(std::io::ErrorKind::NotFound));
+        }
+        self.rm_log();
+        self.rm_tmp();
+        self.recover(&filename)
+    }
+
+    // A new buffer is only created if the current one is used
+    fn open_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        if cl.params.is_empty() {
+            return Err(Error::NoFilename);
+        }
+        if let Some(i) = self.find_buffer(&cl.params[0]) {
+            self.switch_buffer(i);
+            return Ok(State::Running);
+        }
+        if self.filename.is_none() && !self.dirty && self.lines.is_empty() {
+            self.read_only = cl.flag;
+            return self.edit_command(cl.into_cmd("e"));
+        }
+        let previous = self.buffer;
+        self.buffers.push(Buffer::default());
+        self.switch_buffer(self.buffers.len() - 1);
+        self.read_only = cl.flag;
+        let res = self.edit_command(cl.into_cmd("e"));
+        if res.is_err() {
+            self.switch_buffer(previous);
+            self.buffers.pop();
+        }
+        res
+    }
+
+    fn list_buffers_command(&mut self, _cl: CommandLine) -> Result<State, Error> {
+        let n = self.buffers.len();
+        for i in 0..n {
+            let (filename, dirty, len) = if i == self.buffer {
+                (self.filename.clone(), self.dirty, self.lines.len())
+            } else {
+                let b = &self.buffers[i];
+                (b.filename.clone(), b.dirty, b.lines.len())
+            };
+            let line = format!(
+                "{}{} {} ({} lines)",
+                if i == self.buffer { "%" } else { " " },
+                if dirty { "+" } else { " " },
+                filename.as_deref().unwrap_or("[No Name]"),
+                len
+            );
+            self.print(format_line(&line, i + 1, n, true, &self.theme));
+        }
+        Ok(State::Running)
+    }
+
+    fn buffer_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        if cl.params.is_empty() {
+            self.print((self.buffer + 1).to_string());
+            return Ok(State::Running);
+        }
+        let i = cl.params[0].parse::<usize>().map_err(|_| Error::InvalidNumber)?;
+        if i == 0 || i > self.buffers.len() {
+            return Err(Error::NoBuffer);
+        }
+        self.switch_buffer(i - 1);
+        Ok(State::Running)
+    }
+
     fn filename_command(&mut self, cl: CommandLine) -> Result<State, Error> {
         if cl.params.len() == 1 {
-            self.filename = Some(cl.params[0].clone());
+            self.rename(&cl.params[0])?;
         } else if let Some(f) = self.filename.clone() {
-            println!("{}", f);
+            self.print(f);
         } else {
             return Err(Error::NoFilename);
         }
@@ -139,41 +522,65 @@ impl Commands for Editor {
     }
 
     fn write_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let same_file = cl.params.is_empty() || self.filename.as_ref() == Some(&cl.params[0]);
+        if same_file && self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if same_file && !cl.flag && self.is_modified() {
+            return Err(Error::Modified);
+        }
         if cl.params.len() == 1 {
-            self.filename = Some(cl.params[0].clone());
+            self.rename(&cl.params[0])?;
         }
 
         if let Some(f) = self.filename.clone() {
-            let data = self.lines.join("\n") + "\n";
-            fs::write(f, data).expect("Unable to write file");
+            let path = Path::new(&f);
+            if let Some(backup) = self.backup.path(path) {
+                if self.fs.exists(path) {
+                    self.fs.copy(path, &backup).map_err(|e| Error::Io(e.kind()))?;
+                }
+            }
+            let (format, lines) = (self.format, self.lines.as_ref());
+            self.fs.save_with(path, &mut |out| format.write(lines, out)).map_err(|e| Error::Io(e.kind()))?;
+            self.stamp_file();
             self.dirty = false;
+            self.undo_tree.saved = Some(self.undo_tree.current);
+
+            // The journal of a mapped file is replayed on the file itself,
+            // so it has to start again from the file that was written.
+            if self.lines.is_mapped() {
+                self.rm_log();
+                self.history = Vec::new();
+                self.changes = Vec::new();
+                self.undo_tree = UndoTree::new(self.addr);
+            }
             Ok(State::Running)
         } else {
-            return Err(Error::NoFilename);
+            Err(Error::NoFilename)
         }
     }
 
     fn read_command(&mut self, cl: CommandLine) -> Result<State, Error> {
-        if cl.params.len() == 0 {
+        if cl.params.is_empty() {
             return Err(Error::NoFilename);
         }
         if cl.flag {
             let output = process::Command::new("sh").
                 arg("-c").arg(cl.params.join(" ")).
-                output().expect("Could not execute shell command line");
+                output().map_err(|e| Error::Io(e.kind()))?;
             for line in String::from_utf8_lossy(&output.stdout).lines() {
-                self.lines.push(line.to_string());
+                self.splice(self.lines.len(), 0, vec![line.to_string()]);
                 self.addr += 1;
                 self.dirty = true;
             }
             Ok(State::Running)
         } else {
-            match read_lines(&cl.params[0]) {
+            match read_file(self.fs.as_ref(), &cl.params[0], self.encoding) {
                 Err(error) => {
                     return Err(error);
                 },
-                Ok(lines) => {
-                    self.lines.append(&mut lines.clone());
+                Ok((lines, _)) => {
+                    self.splice(self.lines.len(), 0, lines);
                     self.addr = self.lines.len();
                     self.dirty = true;
                 }
@@ -182,38 +589,150 @@ impl Commands for Editor {
         }
     }
 
+    // The encoding, the line ending and the final newline are written back
+    // as they were read, unless they are converted with these commands.
+    fn reload_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        if self.dirty && !cl.flag {
+            return Err(Error::Dirty);
+        }
+        let filename = self.filename.clone().ok_or(Error::NoFilename)?;
+        self.edit_command(cl.into_cmd("e").with_params(&[filename]))
+    }
+
+    // Print the lines to change in the buffer to get the file on disk, in
+    // the unified format without context.
+    fn diff_command(&mut self, _cl: CommandLine) -> Result<State, Error> {
+        let filename = self.filename.clone().ok_or(Error::NoFilename)?;
+        let (lines, _) = read_file(self.fs.as_ref(), &filename, Some(self.format.encoding))?;
+        let buffer = self.lines.to_vec();
+        let edits = diff(&buffer, &lines);
+        let mut i = 0;
+        while i < edits.len() {
+            if let Diff::Same(..) = edits[i] {
+                i += 1;
+                continue;
+            }
+            let j = edits[i..].iter().position(|e| matches!(e, Diff::Same(..))).map_or(edits.len(), |n| i + n);
+            let (a, b) = match edits[..i].last() {
+                Some(Diff::Same(a, b)) => (a + 1, b + 1),
+                _ => (0, 0)
+            };
+            let deleted: Vec<usize> = edits[i..j].iter().filter_map(|e| match e {
+                Diff::Delete(x) => Some(*x),
+                _ => None
+            }).collect();
+            let inserted: Vec<usize> = edits[i..j].iter().filter_map(|e| match e {
+                Diff::Insert(y) => Some(*y),
+                _ => None
+            }).collect();
+
+            // An empty range starts at the line before it like in diff -u
+            let a = if deleted.is_empty() { a } else { a + 1 };
+            let b = if inserted.is_empty() { b } else { b + 1 };
+            self.print(format!("@@ -{},{} +{},{} @@", a, deleted.len(), b, inserted.len()));
+            for x in deleted {
+                self.print(format!("-{}", buffer[x]));
+            }
+            for y in inserted {
+                self.print(format!("+{}", lines[y]));
+            }
+            i = j;
+        }
+        Ok(State::Running)
+    }
+
+    fn encoding_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        if cl.params.len() == 1 {
+            let encoding = Encoding::parse(&cl.params[0]).ok_or(Error::InvalidCommand)?;
+            if encoding != self.format.encoding {
+                self.format.encoding = encoding;
+                self.dirty = true;
+            }
+        } else {
+            self.print(self.format.encoding.name().to_string());
+        }
+        Ok(State::Running)
+    }
+
+    fn ending_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        if cl.params.len() == 1 {
+            let ending = Ending::parse(&cl.params[0]).ok_or(Error::InvalidCommand)?;
+            if ending != self.format.ending {
+                self.format.ending = ending;
+                self.dirty = true;
+            }
+        } else {
+            self.print(self.format.ending.name().to_string());
+        }
+        Ok(State::Running)
+    }
+
+    fn eol_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        if cl.params.len() == 1 {
+            let final_newline = match cl.params[0].as_str() {
+                "on" => true,
+                "off" => false,
+                _ => return Err(Error::InvalidCommand)
+            };
+            if final_newline != self.format.final_newline {
+                self.format.final_newline = final_newline;
+                self.dirty = true;
+            }
+        } else {
+            self.print((if self.format.final_newline { "on" } else { "off" }).to_string());
+        }
+        Ok(State::Running)
+    }
+
+    fn null_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let i = cl.addr_2.unwrap();
+        self.print(self.render_line(i, self.addr, false, None));
+        self.addr = i;
+        Ok(State::Running)
+    }
+
     fn print_command(&mut self, cl: CommandLine) -> Result<State, Error> {
-        let n = self.lines.len();
+        let current = self.addr;
         for i in cl.addr_1.unwrap() .. cl.addr_2.unwrap() + 1 {
-            print_line(&self.lines[i - 1], i, n, false);
+            self.print(self.render_line(i, current, false, None));
             self.addr = i;
         }
         Ok(State::Running)
     }
 
     fn number_command(&mut self, cl: CommandLine) -> Result<State, Error> {
-        let n = self.lines.len();
+        let current = self.addr;
         for i in cl.addr_1.unwrap() .. cl.addr_2.unwrap() + 1 {
-            print_line(&self.lines[i - 1], i, n, true);
+            self.print(self.render_line(i, current, true, None));
             self.addr = i;
         }
         Ok(State::Running)
     }
 
     fn global_command(&mut self, cl: CommandLine) -> Result<State, Error> {
-        let re = Regex::new(&cl.params[0]).unwrap();
-        let cmd_list = if cl.params.len() == 2 { &cl.params[1] } else { "p" };
+        if cl.params.is_empty() {
+            return Err(Error::InvalidCommand);
+        }
+        let mut cmd_list = if cl.params.len() == 2 { cl.params[1].as_str() } else { "p" };
+        let case_insensitive = cmd_list.starts_with('I');
+        if case_insensitive {
+            cmd_list = cmd_list[1..].trim_start();
+        }
+        if cmd_list.is_empty() {
+            cmd_list = "p";
+        }
+        let re = build_regex(&cl.params[0], case_insensitive)?;
         let show_number = cmd_list.ends_with("n");
         let mut i = cl.addr_1.unwrap();
         let mut n = cl.addr_2.unwrap();
         while i <= n {
-            if re.is_match(&self.lines[i - 1]) {
+            if re.is_match(&self.lines.get(i - 1)) {
                 match cmd_list {
                     "p" | "n" | "pn" => {
-                        print_line(&self.lines[i - 1], i, self.lines.len(), show_number);
+                        self.print(self.render_line(i, 0, show_number, Some(&re)));
                     },
                     "d" => {
-                        self.lines.remove(i - 1);
+                        self.splice(i - 1, 1, vec![]);
                         i -= 1;
                         n -= 1;
                     },
@@ -227,21 +746,26 @@ impl Commands for Editor {
     }
 
     fn substitute_command(&mut self, cl: CommandLine) -> Result<State, Error> {
-        let re = Regex::new(&cl.params[0]).unwrap();
-        let limit = if cl.params.len() == 3 {
-            if &cl.params[2] == "g" {
-                0
-            } else {
-                cl.params[2].parse::<usize>().unwrap()
-            }
-        } else {
-            1
+        if cl.params.len() < 2 {
+            return Err(Error::InvalidCommand);
+        }
+        let mut flags = if cl.params.len() == 3 { cl.params[2].as_str() } else { "" };
+        let case_insensitive = flags.ends_with('I');
+        if case_insensitive {
+            flags = &flags[..flags.len() - 1];
+        }
+        let limit = match flags {
+            ""  => 1,
+            "g" => 0,
+            _   => flags.parse::<usize>().map_err(|_| Error::InvalidNumber)?
         };
+        let re = build_regex(&cl.params[0], case_insensitive)?;
         for i in cl.addr_1.unwrap() .. cl.addr_2.unwrap() + 1 {
-            if re.is_match(&self.lines[i - 1]) {
+            if re.is_match(&self.lines.get(i - 1)) {
                 // NOTE: This will replace at most <limit> matches, whereas ed
                 // would replace the <limit> nth match.
-                self.lines[i - 1] = re.replacen(&self.lines[i - 1], limit, cl.params[1].as_str()).to_string();
+                let line = re.replacen(&self.lines.get(i - 1), limit, cl.params[1].as_str()).to_string();
+                self.splice(i - 1, 1, vec![line]);
                 self.addr = i;
                 self.dirty = true;
             }
@@ -249,12 +773,143 @@ impl Commands for Editor {
         Ok(State::Running)
     }
 
-    fn quit_command(&self, cl: CommandLine) -> Result<State, Error> {
-        self.rm_tmp();
-        self.rm_log();
-        if self.dirty && !cl.flag{
+    // The u command toggles between the last two states like in ed, while
+    // undo and redo can move further up and down the tree, and undo with a
+    // parameter jumps to any state listed by undolist.
+    fn undo_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        if cl.cmd == "u" && self.undo_tree.undone {
+            self.redo()?;
+            self.undo_tree.undone = false;
+        } else if cl.params.len() == 1 {
+            let i = cl.params[0].parse::<usize>().map_err(|_| Error::InvalidNumber)?;
+            if i >= self.undo_tree.states.len() {
+                return Err(Error::NoUndo);
+            }
+            let addr = self.undo_tree.states[i].addr_after;
+            self.restore(i, addr);
+        } else {
+            self.undo()?;
+            self.undo_tree.undone = cl.cmd == "u";
+        }
+        Ok(State::Running)
+    }
+
+    fn redo_command(&mut self, _cl: CommandLine) -> Result<State, Error> {
+        self.redo()?;
+        self.undo_tree.undone = false;
+        Ok(State::Running)
+    }
+
+    fn undolist_command(&mut self, _cl: CommandLine) -> Result<State, Error> {
+        let n = self.undo_tree.states.len();
+        let lines: Vec<String> = self.undo_tree.states.iter().enumerate().map(|(i, state)| {
+            let indent = "  ".repeat(self.undo_tree.depth(i));
+            let summary = state.inputs.first().map_or("", |s| s.as_str());
+            let current = if i == self.undo_tree.current { " *" } else { "" };
+            format_line(&format!("{}{}{}", indent, summary, current), i, n, true, &self.theme)
+        }).collect();
+        for line in lines {
+            self.print(line);
+        }
+        Ok(State::Running)
+    }
+
+    // Move chronologically through the states of the undo tree, like g-
+    // and g+ in vim.
+    fn earlier_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let count = if cl.params.len() == 1 {
+            cl.params[0].parse::<usize>().map_err(|_| Error::InvalidNumber)?
+        } else {
+            1
+        };
+        if count > self.undo_tree.current {
+            return Err(Error::NoUndo);
+        }
+        self.undo_command(cl.into_cmd("undo").with_params(&[(self.undo_tree.current - count).to_string()]))
+    }
+
+    fn later_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let count = if cl.params.len() == 1 {
+            cl.params[0].parse::<usize>().map_err(|_| Error::InvalidNumber)?
+        } else {
+            1
+        };
+        let state = match self.undo_tree.current.checked_add(count) {
+            Some(state) if state < self.undo_tree.states.len() => state,
+            _ => return Err(Error::NoRedo)
+        };
+        self.undo_command(cl.into_cmd("undo").with_params(&[state.to_string()]))
+    }
+
+    fn help_command(&mut self, _cl: CommandLine) -> Result<State, Error> {
+        if let Some(error) = self.last_error.clone() {
+            self.print(error_message(&error));
+        }
+        Ok(State::Running)
+    }
+
+    fn help_mode_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        self.show_help = !self.show_help;
+        if self.show_help {
+            self.help_command(cl)
+        } else {
+            Ok(State::Running)
+        }
+    }
+
+    fn help_reference_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let width = HELP.iter().map(|(_, usage, _)| usage.len()).max().unwrap_or(0);
+        let mut found = false;
+        for (cmd, usage, description) in HELP.iter() {
+            if cl.params.is_empty() || cl.params[0] == *cmd {
+                self.print(format!("{:width$}  {}", usage, description, width = width));
+                found = true;
+            }
+        }
+        if found {
+            Ok(State::Running)
+        } else {
+            Err(Error::InvalidCommand)
+        }
+    }
+
+    fn prompt_command(&mut self, _cl: CommandLine) -> Result<State, Error> {
+        self.show_prompt = !self.show_prompt;
+        Ok(State::Running)
+    }
+
+    fn set_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        let names = match cl.params.first() {
+            Some(param) => match param.split_once('=') {
+                Some((name, value)) => {
+                    self.set_option(name.trim(), value.trim())?;
+                    return Ok(State::Running);
+                },
+                None => vec![param.as_str()]
+            },
+            None => OPTIONS.to_vec()
+        };
+        let mut lines = Vec::new();
+        for name in names {
+            let value = self.option(name).ok_or(Error::InvalidOption)?;
+            lines.push(format!("{}={}", name, value));
+        }
+        for line in lines {
+            self.print(line);
+        }
+        Ok(State::Running)
+    }
+
+    fn quit_command(&mut self, cl: CommandLine) -> Result<State, Error> {
+        if (self.dirty || self.buffers.iter().any(|b| b.dirty)) && !cl.flag {
             Err(Error::Dirty)
         } else {
+            for i in 0..self.buffers.len() {
+                self.switch_buffer(i);
+                self.rm_tmp();
+                self.rm_log();
+                self.set_lock(None);
+            }
             Ok(State::Stopped)
         }
     }
@@ -275,18 +930,19 @@ impl Commands for Editor {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::addresses::*;
+    use crate::filesystem::Backup;
+    use crate::filesystem::tests::*;
+    use crate::output::*;
 
-    static TEST_FILE: &str = "LICENSE";
-    static TEST_FILE_LENGTH: usize = 21;
 
     // The a command shall read the given text and append it after the addressed line; the current
     // line number shall become the address of the last inserted line or, if there were none, the
     // addressed line. Address 0 shall be valid for this command; it shall cause the appended text
     // to be placed at the beginning of the buffer.
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_append_command() {
-        let mut ed = Editor::new();
+        let (mut ed, _) = test_editor();
         let mut cl = CommandLine {
             addr_1: None,
             addr_2: None,
@@ -314,4 +970,420 @@ mod tests {
         assert_eq!(ed.addr, 1);
         assert_eq!(ed.insert_mode, true);
     }
+
+    #[test]
+    fn test_substitute_command() {
+        let (mut ed, _) = test_editor();
+        let mut cl = CommandLine {
+            addr_1: None,
+            addr_2: None,
+            cmd: "e".to_string(),
+            flag: false,
+            params: vec![TEST_FILE.to_string()]
+        };
+        ed.edit_command(cl.clone()).ok();
+
+        cl.cmd = "s".to_string();
+        cl.addr_1 = Some(1);
+        cl.addr_2 = Some(TEST_FILE_LENGTH);
+        cl.params = vec!["SOFTWARE".to_string(), "Program".to_string(), "g".to_string()];
+        assert_eq!(ed.substitute_command(cl.clone()), Ok(State::Running));
+        assert_eq!(ed.lines.get(5), "of this software and associated documentation files (the \"Software\"), to deal");
+
+        cl.params[2] = "gI".to_string();
+        assert_eq!(ed.substitute_command(cl.clone()), Ok(State::Running));
+        assert_eq!(ed.lines.get(5), "of this Program and associated documentation files (the \"Program\"), to deal");
+    }
+
+    #[test]
+    fn test_lazy_edit() {
+        let (mut ed, fs) = test_editor();
+        ed.lazy_size = 0;
+        assert_eq!(ed.exec(&format!("e {}", TEST_FILE)), Ok(State::Running));
+        assert!(ed.lines.is_mapped());
+        assert_eq!(ed.lines.len(), TEST_FILE_LENGTH);
+        assert_eq!(ed.exec("1d"), Ok(State::Running));
+        assert_eq!(ed.exec("u"), Ok(State::Running));
+        assert_eq!(ed.exec("w"), Ok(State::Running));
+        assert_eq!(fs.get(TEST_FILE), Some(TEST_DATA.as_bytes().to_vec()));
+
+        // The journal is replayed on the file that was last written
+        assert_eq!(ed.exec("1d"), Ok(State::Running));
+        assert_eq!(ed.exec("w"), Ok(State::Running));
+        assert_eq!(ed.exec("1d"), Ok(State::Running));
+        drop(ed);
+        let (mut ed, _) = test_editor();
+        ed.fs = Box::new(fs.clone());
+        ed.lazy_size = 0;
+        assert_eq!(ed.recover(TEST_FILE), Ok(State::Running));
+        assert_eq!(ed.lines.len(), TEST_FILE_LENGTH - 2);
+        assert_eq!(ed.lines.get(0), "Copyright (c) 2019 Vincent Ollivier");
+    }
+
+    #[test]
+    fn test_ending_command() {
+        let (mut ed, fs) = test_editor();
+        fs.insert("dos.txt", b"a\r\nb");
+        assert_eq!(ed.exec("e dos.txt"), Ok(State::Running));
+        assert_eq!(ed.lines.to_vec(), vec!["a", "b"]);
+        assert_eq!(ed.exec("1s/a/c/"), Ok(State::Running));
+        assert_eq!(ed.exec("w"), Ok(State::Running));
+        assert_eq!(fs.get("dos.txt"), Some(b"c\r\nb".to_vec()));
+
+        let output = Capture::new();
+        ed.output = Box::new(output.clone());
+        assert_eq!(ed.exec("ending"), Ok(State::Running));
+        assert_eq!(ed.exec("eol"), Ok(State::Running));
+        assert_eq!(output.take_lines(), vec!["crlf", "off"]);
+        assert_eq!(ed.exec("ending lf"), Ok(State::Running));
+        assert_eq!(ed.exec("eol on"), Ok(State::Running));
+        assert!(ed.dirty);
+        assert_eq!(ed.exec("w"), Ok(State::Running));
+        assert_eq!(fs.get("dos.txt"), Some(b"c\nb\n".to_vec()));
+    }
+
+    #[test]
+    fn test_encoding_command() {
+        let (mut ed, fs) = test_editor();
+        fs.insert("latin1.txt", b"caf\xe9\n");
+        fs.insert("binary", b"\x00\x9f\xff\n\xc3");
+        assert_eq!(ed.exec("e binary"), Ok(State::Running));
+        assert_eq!(ed.exec("1s/^/x/"), Ok(State::Running));
+        assert_eq!(ed.exec("w"), Ok(State::Running));
+        assert_eq!(fs.get("binary"), Some(b"x\x00\x9f\xff\n\xc3".to_vec()));
+
+        assert_eq!(ed.exec("e latin1.txt"), Ok(State::Running));
+        assert_eq!(ed.lines.get(0), "caf\u{e9}");
+        assert_eq!(ed.exec("encoding utf8"), Ok(State::Running));
+        assert_eq!(ed.exec("w"), Ok(State::Running));
+        assert_eq!(fs.get("latin1.txt"), Some("caf\u{e9}\n".as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_write_backup() {
+        let (mut ed, fs) = test_editor();
+        ed.backup = Backup::Simple;
+        ed.exec(&format!("e {}", TEST_FILE)).ok();
+        ed.exec("2,$d").ok();
+        assert_eq!(ed.exec("w"), Ok(State::Running));
+        assert_eq!(fs.get(format!("{}~", TEST_FILE)), Some(TEST_DATA.as_bytes().to_vec()));
+        assert_eq!(fs.get(TEST_FILE), Some(b"The MIT License (MIT)\n".to_vec()));
+    }
+
+    #[test]
+    fn test_modified_file() {
+        let (mut ed, fs) = test_editor();
+        fs.insert("file", b"a\nb\nc\n");
+        ed.exec("e file").ok();
+        ed.exec("2d").ok();
+        fs.insert("file", b"a\nb\nc\nd\n");
+        assert_eq!(ed.exec("w"), Err(Error::Modified));
+
+        let output = Capture::new();
+        ed.output = Box::new(output.clone());
+        assert_eq!(ed.exec("diff"), Ok(State::Running));
+        assert_eq!(output.take_lines(), vec!["@@ -1,0 +2,1 @@", "+b", "@@ -2,0 +4,1 @@", "+d"]);
+
+        assert_eq!(ed.exec("reload"), Err(Error::Dirty));
+        assert_eq!(ed.exec("w!"), Ok(State::Running));
+        assert_eq!(fs.get("file"), Some(b"a\nc\n".to_vec()));
+        fs.insert("file", b"a\nc\nd\n");
+        assert_eq!(ed.exec("reload"), Ok(State::Running));
+        assert_eq!(ed.lines.len(), 3);
+        assert_eq!(ed.exec("w"), Ok(State::Running));
+    }
+
+    #[test]
+    fn test_move_and_copy_commands() {
+        let (mut ed, fs) = test_editor();
+        fs.insert("file", b"a\nb\nc\nd\n");
+        ed.exec("e file").ok();
+        assert_eq!(ed.exec("1,2m$"), Ok(State::Running));
+        assert_eq!(ed.lines.to_vec(), vec!["c", "d", "a", "b"]);
+        assert_eq!(ed.addr, 4);
+        assert_eq!(ed.exec("1,3m2"), Err(Error::InvalidAddress));
+        for target in &["/", "/x", "é", "1:/"] {
+            assert_eq!(ed.exec(&format!("1t {}", target)), Err(Error::InvalidAddress));
+            assert_eq!(ed.exec(&format!("1m {}", target)), Err(Error::InvalidAddress));
+        }
+        assert_eq!(ed.exec("3kx"), Ok(State::Running));
+        assert_eq!(ed.exec("1t0"), Ok(State::Running));
+        assert_eq!(ed.lines.to_vec(), vec!["c", "c", "d", "a", "b"]);
+        assert_eq!(ed.exec("'xp"), Ok(State::Running));
+        assert_eq!(ed.addr, 4);
+        assert_eq!(ed.exec("u"), Ok(State::Running));
+        assert_eq!(ed.lines.to_vec(), vec!["c", "d", "a", "b"]);
+    }
+
+    #[test]
+    fn test_marks() {
+        let (mut ed, fs) = test_editor();
+        fs.insert("file", b"a\nb\nc\n");
+        ed.exec("e file").ok();
+
+        // Marks follow their lines through undo and redo
+        assert_eq!(ed.exec("2kx"), Ok(State::Running));
+        assert_eq!(ed.exec("1d"), Ok(State::Running));
+        assert_eq!(ed.exec("u"), Ok(State::Running));
+        assert_eq!(ed.exec("'xp"), Ok(State::Running));
+        assert_eq!(ed.addr, 2);
+        assert_eq!(ed.exec("redo"), Ok(State::Running));
+        assert_eq!(ed.exec("'xp"), Ok(State::Running));
+        assert_eq!(ed.addr, 1);
+
+        // A line changed by s keeps its mark, unlike a deleted one
+        assert_eq!(ed.exec("1s/b/B/"), Ok(State::Running));
+        assert_eq!(ed.marks.get(&'x'), Some(&1));
+        assert_eq!(ed.exec("1d"), Ok(State::Running));
+        assert_eq!(ed.exec("'xp"), Err(Error::InvalidAddress));
+
+        // The marks of a file are not kept for the next one
+        assert_eq!(ed.exec("1kx"), Ok(State::Running));
+        assert_eq!(ed.exec("e! file"), Ok(State::Running));
+        assert!(ed.marks.is_empty());
+    }
+
+    #[test]
+    fn test_buffer_commands() {
+        let (mut ed, fs) = test_editor();
+        fs.insert("file", b"a\nb\n");
+        ed.exec(&format!("e {}", TEST_FILE)).ok();
+        assert_eq!(ed.exec("open file"), Ok(State::Running));
+        assert_eq!(ed.buffer, 1);
+        assert_eq!(ed.exec("2d"), Ok(State::Running));
+        assert_eq!(ed.exec("1t 1:0"), Ok(State::Running));
+        assert_eq!(ed.exec("1m 1:$"), Ok(State::Running));
+        assert!(ed.lines.is_empty());
+
+        let output = Capture::new();
+        ed.output = Box::new(output.clone());
+        assert_eq!(ed.exec("ls"), Ok(State::Running));
+        assert_eq!(output.take_lines().len(), 2);
+        assert_eq!(ed.exec("buffer 3"), Err(Error::NoBuffer));
+        assert_eq!(ed.exec("buffer 1"), Ok(State::Running));
+        assert_eq!(ed.lines.len(), TEST_FILE_LENGTH + 2);
+        assert_eq!(ed.lines.get(0), "a");
+        assert_eq!(ed.lines.get(TEST_FILE_LENGTH + 1), "a");
+
+        // Each buffer has its own undo tree
+        assert_eq!(ed.exec("u"), Ok(State::Running));
+        assert_eq!(ed.lines.len(), TEST_FILE_LENGTH + 1);
+        assert_eq!(ed.exec("open file"), Ok(State::Running));
+        assert_eq!(ed.exec("u"), Ok(State::Running));
+        assert_eq!(ed.lines.to_vec(), vec!["a"]);
+
+        // Editing a file opened in another buffer switches to it
+        assert_eq!(ed.exec("buffer 1"), Ok(State::Running));
+        assert_eq!(ed.exec("e ./file"), Ok(State::Running));
+        assert_eq!(ed.buffer, 1);
+        assert_eq!(ed.lines.to_vec(), vec!["a"]);
+        assert_eq!(ed.buffers.len(), 2);
+
+        assert_eq!(ed.exec("q"), Err(Error::Dirty));
+        assert_eq!(ed.exec("q!"), Ok(State::Stopped));
+        assert!(!ed.has_journal(TEST_FILE));
+        assert!(!ed.has_journal("file"));
+    }
+
+    #[test]
+    fn test_registers() {
+        let (mut ed, fs) = test_editor();
+        fs.insert("file", b"a\nb\nc\n");
+        ed.exec("e file").ok();
+        assert_eq!(ed.exec("pu"), Err(Error::EmptyRegister));
+        assert_eq!(ed.exec("1,2y a"), Ok(State::Running));
+        assert_eq!(ed.exec("3y A"), Ok(State::Running));
+        assert_eq!(ed.exec("1y ab"), Err(Error::InvalidCommand));
+        assert_eq!(ed.exec("3d"), Ok(State::Running));
+        assert_eq!(ed.registers[&'"'], vec!["c"]);
+
+        // The registers are kept when editing another file
+        ed.exec("w").ok();
+        ed.exec(&format!("e {}", TEST_FILE)).ok();
+        assert_eq!(ed.exec("0pu a"), Ok(State::Running));
+        assert_eq!(ed.addr, 3);
+        assert_eq!(ed.exec("$pu"), Ok(State::Running));
+        assert_eq!(ed.lines.get(0), "a");
+        assert_eq!(ed.lines.get(2), "c");
+        assert_eq!(ed.lines.len(), TEST_FILE_LENGTH + 4);
+        assert_eq!(ed.exec("u"), Ok(State::Running));
+        assert_eq!(ed.lines.len(), TEST_FILE_LENGTH + 3);
+
+        let output = Capture::new();
+        ed.output = Box::new(output.clone());
+        assert_eq!(ed.exec("registers"), Ok(State::Running));
+        assert_eq!(output.take_lines(), vec!["\"\" c (1 lines)", "\"a a (3 lines)"]);
+    }
+
+    #[test]
+    fn test_macros() {
+        let (mut ed, fs) = test_editor();
+        fs.insert("file", b"a\nb\nc\nd\n");
+        ed.exec("e file").ok();
+        assert_eq!(ed.exec("1@a"), Err(Error::EmptyRegister));
+        ed.addr = 1;
+        assert_eq!(ed.exec("rec a"), Ok(State::Running));
+        assert_eq!(ed.exec("s/$/!/"), Ok(State::Running));
+        assert_eq!(ed.exec("1zz"), Err(Error::InvalidCommand));
+        assert_eq!(ed.exec("a"), Ok(State::Running));
+        assert_eq!(ed.exec("-"), Ok(State::Running));
+        assert_eq!(ed.exec("."), Ok(State::Running));
+        assert_eq!(ed.exec("rec"), Ok(State::Running));
+        assert_eq!(ed.registers[&'a'], vec!["s/$/!/", "a", "-", "."]);
+        assert_eq!(ed.lines.to_vec(), vec!["a!", "-", "b", "c", "d"]);
+
+        // The whole replay is undone at once
+        assert_eq!(ed.exec("4@a 2"), Ok(State::Running));
+        assert_eq!(ed.lines.to_vec(), vec!["a!", "-", "b", "c!", "-!", "-", "d"]);
+        assert_eq!(ed.exec("u"), Ok(State::Running));
+        assert_eq!(ed.lines.to_vec(), vec!["a!", "-", "b", "c", "d"]);
+        assert_eq!(ed.addr, 2);
+
+        // The macros can be read in another session
+        assert_eq!(ed.exec("wmacros macros"), Ok(State::Running));
+        let (mut ed, _) = test_editor();
+        ed.fs = Box::new(fs.clone());
+        assert_eq!(ed.exec("rmacros macros"), Ok(State::Running));
+        assert_eq!(ed.registers[&'a'], vec!["s/$/!/", "a", "-", "."]);
+        assert_eq!(ed.exec("rmacros file"), Err(Error::Io(std::io::ErrorKind::InvalidData)));
+    }
+
+    #[test]
+    fn test_set_command() {
+        let (mut ed, _) = test_editor();
+        let output = Capture::new();
+        ed.output = Box::new(output.clone());
+        assert_eq!(ed.exec("set prompt = \"{line}> \""), Ok(State::Running));
+        assert_eq!(ed.exec("set backup=simple"), Ok(State::Running));
+        assert_eq!(ed.exec("set encoding=latin1"), Ok(State::Running));
+        assert_eq!(ed.exec("set lazy=0"), Ok(State::Running));
+        assert_eq!(ed.exec("set help=on"), Ok(State::Running));
+        assert_eq!(ed.render_prompt(), "0> ");
+        assert_eq!(ed.backup, Backup::Simple);
+        assert_eq!(ed.encoding, Some(Encoding::Latin1));
+        assert_eq!(ed.lazy_size, 0);
+        assert!(ed.show_help);
+        assert_eq!(ed.exec("set lazy=big"), Err(Error::InvalidOption));
+        assert_eq!(ed.exec("set color=red"), Err(Error::InvalidOption));
+
+        assert_eq!(ed.exec("set backup"), Ok(State::Running));
+        assert_eq!(output.take_lines(), vec!["backup=simple"]);
+        assert_eq!(ed.exec("set"), Ok(State::Running));
+        assert_eq!(output.take_lines().len(), OPTIONS.len());
+    }
+
+    #[test]
+    fn test_source() {
+        let (mut ed, fs) = test_editor();
+        fs.insert(".nedrc", b"set backup=timestamped\nset nothing=1\nrmacros\n");
+        assert!(!ed.is_trusted(Path::new(".nedrc")));
+        assert_eq!(ed.trust(Path::new(".nedrc")), Ok(()));
+        assert!(ed.is_trusted(Path::new(".nedrc")));
+        assert_eq!(ed.source(Path::new(".nedrc"), true), Ok(State::Running));
+        assert_eq!(ed.backup, Backup::Timestamped);
+
+        // A change to the file needs to be trusted again
+        fs.insert(".nedrc", b"set backup=none\n");
+        assert!(!ed.is_trusted(Path::new(".nedrc")));
+        assert_eq!(ed.trust(Path::new(".nedrc")), Ok(()));
+        assert!(ed.is_trusted(Path::new(".nedrc")));
+    }
+
+    #[test]
+    fn test_undo_command() {
+        let (mut ed, _) = test_editor();
+        ed.exec(&format!("e {}", TEST_FILE)).ok();
+        ed.exec("1d").ok();
+        assert_eq!(ed.lines.len(), TEST_FILE_LENGTH - 1);
+
+        // The u command undoes itself
+        assert_eq!(ed.exec("u"), Ok(State::Running));
+        assert_eq!(ed.lines.len(), TEST_FILE_LENGTH);
+        assert_eq!(ed.addr, TEST_FILE_LENGTH);
+        assert_eq!(ed.exec("u"), Ok(State::Running));
+        assert_eq!(ed.lines.len(), TEST_FILE_LENGTH - 1);
+        assert_eq!(ed.addr, 1);
+
+        // Undone changes are kept in another branch of the tree
+        ed.exec("undo").ok();
+        ed.exec("2d").ok();
+        assert_eq!(ed.lines.get(1), "Copyright (c) 2019 Vincent Ollivier");
+        ed.exec("undo 1").ok();
+        assert_eq!(ed.lines.get(0), "");
+        assert_eq!(ed.exec("redo"), Err(Error::NoRedo));
+        assert_eq!(ed.exec("later"), Ok(State::Running));
+        assert_eq!(ed.exec(&format!("later {}", usize::MAX)), Err(Error::NoRedo));
+        assert_eq!(ed.lines.get(0), "The MIT License (MIT)");
+        assert_eq!(ed.lines.get(1), "Copyright (c) 2019 Vincent Ollivier");
+
+        // Undoing and redoing a shell command doesn't run it again
+        ed.exec("r!date +%N").ok();
+        let line = ed.lines.get(ed.lines.len() - 1).to_string();
+        ed.exec("u").ok();
+        ed.exec("u").ok();
+        assert_eq!(ed.lines.get(ed.lines.len() - 1), line);
+    }
+
+    #[test]
+    fn test_help_command() {
+        let (mut ed, _) = test_editor();
+        assert_eq!(ed.exec("h"), Ok(State::Running));
+        assert_eq!(ed.last_error, None);
+        assert_eq!(ed.exec("p"), Err(Error::InvalidAddress));
+        assert_eq!(ed.last_error, Some(Error::InvalidAddress));
+        assert!(!ed.show_help);
+        assert_eq!(ed.exec("H"), Ok(State::Running));
+        assert!(ed.show_help);
+        assert_eq!(ed.exec("help z"), Err(Error::InvalidCommand));
+        assert_eq!(ed.exec("help d"), Ok(State::Running));
+        assert_eq!(ed.exec("help help"), Ok(State::Running));
+    }
+
+    #[test]
+    fn test_prompt_command() {
+        let (mut ed, _) = test_editor();
+        ed.exec(&format!("e {}", TEST_FILE)).ok();
+        ed.exec("2d").ok();
+        ed.prompt = "ned:{file}:{line}{dirty}> ".to_string();
+        assert_eq!(ed.render_prompt(), "ned:LICENSE:2*> ");
+        assert_eq!(ed.exec("P"), Ok(State::Running));
+        assert_eq!(ed.render_prompt(), "");
+    }
+
+    #[test]
+    fn test_print_command() {
+        let (mut ed, _) = test_editor();
+        let output = Capture::new();
+        ed.output = Box::new(output.clone());
+        ed.exec(&format!("e {}", TEST_FILE)).ok();
+        assert_eq!(ed.exec("1,3p"), Ok(State::Running));
+        assert_eq!(output.take_lines(), vec![
+            "The MIT License (MIT)",
+            "",
+            "Copyright (c) 2019 Vincent Ollivier"
+        ]);
+        assert_eq!(ed.addr, 3);
+        assert_eq!(output.take_lines(), Vec::<String>::new());
+
+        ed.exec("f").ok();
+        ed.exec("h").ok();
+        assert_eq!(output.take_lines(), vec![TEST_FILE]);
+        ed.print_error(Error::NoUndo);
+        assert_eq!(output.take_errors().len(), 1);
+
+        // A newline alone prints the next line, and an address alone the
+        // last addressed line, like in ed
+        assert_eq!(ed.exec(""), Ok(State::Running));
+        assert_eq!(ed.exec("1"), Ok(State::Running));
+        assert_eq!(ed.exec("2,3"), Ok(State::Running));
+        assert_eq!(output.take_lines(), vec![
+            "",
+            "The MIT License (MIT)",
+            "Copyright (c) 2019 Vincent Ollivier"
+        ]);
+        assert_eq!(ed.addr, 3);
+        assert_eq!(ed.exec("$"), Ok(State::Running));
+        assert_eq!(ed.exec(""), Err(Error::InvalidAddress));
+        assert_eq!(ed.addr, TEST_FILE_LENGTH);
+    }
 }
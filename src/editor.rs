@@ -1,13 +1,33 @@
 use crate::addresses::*;
+use crate::buffer::*;
 use crate::commands::*;
+use crate::filesystem::*;
+use crate::format::*;
+use crate::lock;
+use crate::output::*;
+use crate::storage::*;
+use crate::theme::*;
+use crate::undo::*;
 use crate::utils::*;
-use std::fs;
+use std::collections::BTreeMap;
+use std::env;
+use std::mem;
+use std::io;
+use std::process;
+use std::path::{Path, PathBuf};
 use regex::Regex;
 
 static RE_ADDRS: &str =
-    r"^(?P<addr1>/(?:[^\\/]|\\.)*/|[.$]|[-+]?[0-9]*)(?P<sep>[,;%]?)(?P<addr2>/(?:[^\\/]|\\.)*/|[.$]|[-+]?[0-9]*)";
+    r"^(?P<addr1>/(?:[^\\/]|\\.)*/I?|'[a-z]|[.$]|[-+]?[0-9]*)(?P<sep>[,;%]?)(?P<addr2>/(?:[^\\/]|\\.)*/I?|'[a-z]|[.$]|[-+]?[0-9]*)";
+static PROMPT: &str = "> ";
+static LAZY_SIZE: u64 = 64 << 20;
+pub static MACRO_DEPTH: usize = 100;
+pub static OPTIONS: [&str; 13] = [
+    "prompt", "help", "backup", "encoding", "lazy", "statedir", "historyfile",
+    "color", "errorcolor", "numbercolor", "linecolor", "matchcolor", "promptcolor"
+];
 static RE_CMD: &str =
-    r"^(?P<cmd>[a-z]*)(?P<flag>!?)(?:/(?P<re1>(?:[^\\/]|\\.)*)/(?P<re2>(?:[^\\/]|\\.)*)?)?(?:[ /](?P<params>.*))?";
+    r"^(?P<cmd>@|[a-zA-Z]*)(?P<flag>!?)(?:/(?P<re1>(?:[^\\/]|\\.)*)/(?P<re2>(?:[^\\/]|\\.)*)?)?(?:[ /]?(?P<params>.*))?";
 
 #[derive(Debug, PartialEq)]
 pub enum State {
@@ -15,26 +35,96 @@ pub enum State {
     Stopped
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Error {
     InvalidCommand,
     InvalidAddress,
+    InvalidNumber,
+    InvalidRegex,
+    NoMatch,
     NoFilename,
-    CannotOpen,
+    CannotOpen(io::ErrorKind),
     NoUndo,
-    Dirty
+    NoRedo,
+    Io(io::ErrorKind),
+    Dirty,
+    Modified,
+    Locked(u32),
+    ReadOnly,
+    NoBuffer,
+    EmptyRegister,
+    InvalidOption,
+    Recoverable,
+    NoJournal(io::ErrorKind)
+}
+
+impl Error {
+    // The exit status of ned when it is stopped by an error, to distinguish
+    // a file that could not be opened, unsaved changes, a locked file, a
+    // journal left by a crash, and failed commands.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::CannotOpen(_) => 2,
+            Error::Dirty => 3,
+            Error::Locked(_) => 4,
+            Error::Recoverable => 5,
+            _ => 1
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct Editor {
-    pub dirty: bool,
-    pub show_help: bool,
-    pub show_debug: bool,
-    pub insert_mode: bool,
-    pub filename: Option<String>,
-    pub addr: usize,
-    pub lines: Vec<String>,
-    pub history: Vec<String>
+    pub(crate) dirty: bool,
+    pub(crate) show_help: bool,
+    pub(crate) show_debug: bool,
+    pub(crate) show_prompt: bool,
+    pub(crate) insert_mode: bool,
+    pub(crate) read_only: bool,
+    pub(crate) journaled: bool,
+    pub(crate) last_error: Option<Error>,
+    pub(crate) prompt: String,
+    pub(crate) filename: Option<String>,
+    pub(crate) stamp: Option<Stamp>,
+    pub(crate) format: Format,
+    pub(crate) encoding: Option<Encoding>,
+    pub(crate) state_dir: PathBuf,
+    pub(crate) history_file: PathBuf,
+    pub(crate) lazy_size: u64,
+    pub(crate) backup: Backup,
+    pub(crate) addr: usize,
+    pub(crate) lines: Box<dyn Storage>,
+    pub(crate) marks: BTreeMap<char, usize>,
+    pub(crate) registers: BTreeMap<char, Vec<String>>,
+    pub(crate) recording: Option<char>,
+    pub(crate) macro_depth: usize,
+    pub(crate) history: Vec<String>,
+    pub(crate) output: Box<dyn Output>,
+    pub(crate) theme: Theme,
+    pub(crate) fs: Box<dyn Filesystem>,
+    pub(crate) undo_tree: UndoTree,
+    pub(crate) undo_addr: usize,
+    pub(crate) changes: Vec<Change>,
+    pub(crate) lock: Option<PathBuf>,
+    pub(crate) buffers: Vec<Buffer>,
+    pub(crate) buffer: usize
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Editor::new()
+    }
+}
+
+// The journals are kept when the editor is dropped without quitting, but
+// the locks are released.
+impl Drop for Editor {
+    fn drop(&mut self) {
+        for i in 0..self.buffers.len() {
+            self.switch_buffer(i);
+            self.set_lock(None);
+        }
+    }
 }
 
 impl Editor {
@@ -42,63 +132,185 @@ impl Editor {
         Editor {
             dirty: false,
             show_debug: false,
-            show_help: true,
+            show_help: false,
+            show_prompt: true,
             insert_mode: false,
+            read_only: false,
+            journaled: true,
+            last_error: None,
+            prompt: PROMPT.to_string(),
             filename: None,
+            stamp: None,
+            format: Format::default(),
+            encoding: None,
+            state_dir: default_state_dir(),
+            history_file: PathBuf::from(env::var("HOME").unwrap_or_default()).join(".ned_history"),
+            lazy_size: LAZY_SIZE,
+            backup: Backup::None,
             addr: 0,
-            lines: Vec::new(),
+            lines: Box::new(Rope::new()),
+            marks: BTreeMap::new(),
+            registers: BTreeMap::new(),
+            recording: None,
+            macro_depth: 0,
             history: Vec::new(),
+            output: Box::new(Stdout),
+            theme: Theme::default(),
+            fs: Box::new(RealFs),
+            undo_tree: UndoTree::new(0),
+            undo_addr: 0,
+            changes: Vec::new(),
+            lock: None,
+            buffers: vec![Buffer::default()],
+            buffer: 0,
         }
     }
 
-    pub fn parse_command_line(&self, input: &str) -> CommandLine {
+    // The state of the editor is only changed by commands, except for the
+    // settings of the front end and what the editor is plugged into.
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn insert_mode(&self) -> bool {
+        self.insert_mode
+    }
+
+    pub fn set_show_prompt(&mut self, show_prompt: bool) {
+        self.show_prompt = show_prompt;
+    }
+
+    pub fn set_show_debug(&mut self, show_debug: bool) {
+        self.show_debug = show_debug;
+    }
+
+    pub fn set_color_mode(&mut self, mode: ColorMode) {
+        self.theme.set_mode(mode);
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Output>) {
+        self.output = output;
+    }
+
+    pub fn set_filesystem(&mut self, fs: Box<dyn Filesystem>) {
+        self.fs = fs;
+    }
+
+    pub fn history_file(&self) -> &Path {
+        &self.history_file
+    }
+
+    pub fn config_file(&self) -> PathBuf {
+        default_config_file()
+    }
+
+    // The prompt can contain the name of the file, the current line, a dirty
+    // marker, and the number of lines in the buffer, like in
+    // "ned:{file}:{line}{dirty}> ".
+    pub fn render_prompt(&self) -> String {
+        if self.insert_mode || !self.show_prompt {
+            return String::new();
+        }
+        let prompt = self.prompt.
+            replace("{file}", self.filename.as_deref().unwrap_or("")).
+            replace("{line}", &self.addr.to_string()).
+            replace("{dirty}", if self.dirty { "*" } else { "" }).
+            replace("{len}", &self.lines.len().to_string());
+        self.theme.paint(&prompt, self.theme.prompt)
+    }
+
+    // A line as printed by p, n and g, with the current line and the matches
+    // of the regex highlighted.
+    pub(crate) fn render_line(&self, i: usize, current: usize, show_number: bool, re: Option<&Regex>) -> String {
+        let line = self.lines.get(i - 1);
+        let line = match re {
+            Some(re) => self.theme.paint_matches(&line, re),
+            None if i == current => self.theme.paint(&line, self.theme.line),
+            None => line.into_owned()
+        };
+        format_line(&line, i, self.lines.len(), show_number, &self.theme)
+    }
+
+    pub(crate) fn parse_command_line(&self, input: &str) -> Result<CommandLine, Error> {
         let re = Regex::new(RE_ADDRS).unwrap();
         let caps = re.captures(input).unwrap();
 
-        let addr_1 = self.parse_addr_1(&caps["addr1"], &caps["sep"]);
-        let addr_2 = self.parse_addr_2(&caps["addr2"], &caps["sep"]);
+        let addr_1 = self.parse_addr_1(&caps["addr1"], &caps["sep"])?;
+        let addr_2 = self.parse_addr_2(&caps["addr2"], &caps["sep"])?;
 
         let i = caps[0].len();
         let re = Regex::new(RE_CMD).unwrap();
         let caps = re.captures(&input[i..]).unwrap();
 
-        let cmd = caps["cmd"].to_string();
+        let mut cmd = caps["cmd"].to_string();
         let flag = &caps["flag"] == "!";
 
         let mut params = vec![];
+        if cmd.len() == 2 && cmd.starts_with('k') && cmd.as_bytes()[1].is_ascii_lowercase() {
+            params.push(cmd[1..].to_string()); // The mark of kx like in ed
+            cmd.truncate(1);
+        }
         if let Some(m) = caps.name("re1") {
             params.push(m.as_str().to_string());
         }
         if let Some(m) = caps.name("re2") {
             params.push(m.as_str().to_string());
         }
-        if let Some(m) = caps.name("params") {
+        if cmd == "set" {
+            params.extend(caps.name("params").map(|m| m.as_str().trim().to_string()).filter(|p| !p.is_empty()));
+        } else if let Some(m) = caps.name("params") {
             for param in m.as_str().split_whitespace() {
                 params.push(param.to_string());
             }
         }
 
-        CommandLine { addr_1, addr_2, cmd, flag, params }
+        Ok(CommandLine { addr_1, addr_2, cmd, flag, params })
     }
 
+    // The inputs are recorded when they succeed, except the rec commands
+    // starting and stopping the recording, and the ones run by a macro.
     pub fn exec(&mut self, input: &str) -> Result<State, Error> {
+        let recording = self.recording.filter(|_| self.macro_depth == 0);
+        let res = self.exec_input(input);
+        match &res {
+            Err(error) => {
+                self.last_error = Some(error.clone());
+            },
+            Ok(_) => {
+                if let Some(name) = recording.filter(|name| self.recording == Some(*name)) {
+                    self.registers.entry(name).or_default().push(input.to_string());
+                }
+            }
+        }
+        res
+    }
+
+    fn exec_input(&mut self, input: &str) -> Result<State, Error> {
         if self.insert_mode {
             if input == "." {
                 self.insert_mode = false;
             } else {
-                self.lines.insert(self.addr, input.to_string());
+                self.splice(self.addr, 0, vec![input.to_string()]);
                 self.addr += 1;
                 self.dirty = true;
             }
             self.log(input);
+            self.commit();
             return Ok(State::Running);
         }
+        self.undo_addr = self.addr;
 
-        let mut cl = self.parse_command_line(input);
+        let mut cl = self.parse_command_line(input)?;
 
-        if !self.is_range_ok(cl.clone()) {
-            return Err(Error::InvalidAddress);
+        // A newline alone goes to the next line, like .+1 in ed
+        if cl.cmd.is_empty() && cl.addr_1.is_none() {
+            cl.addr_1 = Some(self.addr + 1);
         }
+
         let mut loggable_input = input.to_string();
         if cl.addr_1.is_none() {
             loggable_input = format!("{}{}", self.addr, input);
@@ -107,74 +319,604 @@ impl Editor {
         if cl.addr_2.is_none() {
             cl.addr_2 = cl.addr_1;
         }
+        if !self.is_range_ok(cl.clone()) {
+            return Err(Error::InvalidAddress);
+        }
 
         if self.show_debug {
-            println!("# {:?}", self);
+            self.print(format!("# {:?}", self));
         }
 
-        if cl.is_undo() {
-            self.undo()
-        } else {
-            if cl.is_undoable() {
-                self.log(&loggable_input);
-            }
-            self.command(cl)
+        let is_undoable = cl.is_undoable();
+        let res = self.command(cl);
+        if is_undoable && res.is_ok() {
+            self.log(&loggable_input);
         }
+        self.commit();
+        res
     }
 
-    pub fn log(&mut self, input: &str) {
+    pub fn print(&mut self, line: String) {
+        self.output.print(&line);
+    }
+
+    pub fn print_error(&mut self, error: Error) {
+        let line = format_error(&error, self.show_help, &self.theme);
+        self.output.print_error(&line);
+    }
+
+    pub(crate) fn log(&mut self, input: &str) {
         self.history.push(input.to_string());
-        self.mk_log();
+        self.mk_log().ok(); // The buffer is not journaled if mk_tmp failed
+    }
+
+    // Replace `n` lines of the buffer at index `at` with the `new` ones,
+    // keeping track of the change to be able to undo it.
+    pub(crate) fn splice(&mut self, at: usize, n: usize, new: Vec<String>) {
+        let old = self.lines.splice(at, n, new.clone());
+        move_marks(&mut self.marks, at, n, new.len());
+
+        if let Some(last) = self.changes.last_mut() {
+            if old.is_empty() && last.at + last.new.len() == at {
+                last.new.extend(new); // Merge consecutive insertions
+                return;
+            }
+        }
+        self.changes.push(Change { at, old, new });
+    }
+
+    // Create a new state in the undo tree once a command has been fully
+    // entered, including the text typed in insert mode, or once a macro has
+    // been fully replayed.
+    pub(crate) fn commit(&mut self) {
+        if self.insert_mode || self.macro_depth > 0 {
+            return;
+        }
+        let n = self.undo_tree.history_len();
+        if self.history.len() > n || !self.changes.is_empty() {
+            let inputs = self.history[n..].to_vec();
+            let changes = std::mem::take(&mut self.changes);
+            self.undo_tree.commit(inputs, changes, self.undo_addr, self.addr);
+        }
     }
 
-    pub fn mk_log(&mut self) {
-        if let Some(filename) = self.filename.clone() {
-            let file = format!(".{}.ned", filename);
-            let data = self.history.join("\n") + "\n";
-            fs::write(file, data).expect("Unable to write ned file");
+    pub(crate) fn absolute_path(&self, filename: &str) -> PathBuf {
+        let path = match env::current_dir() {
+            Ok(dir) => dir.join(filename),
+            Err(_) => PathBuf::from(filename)
+        };
+        self.fs.canonicalize(&path)
+    }
+
+    // The journal and the backup of a file are kept in the state directory
+    // under its escaped absolute path.
+    pub(crate) fn journal_path(&self, filename: &str, ext: &str) -> PathBuf {
+        let path = self.absolute_path(filename);
+        self.state_dir.join(format!("{}.{}", escape_path(&path), ext))
+    }
+
+    pub(crate) fn log_path(&self, filename: &str) -> PathBuf {
+        self.journal_path(filename, "ned")
+    }
+
+    pub(crate) fn tmp_path(&self, filename: &str) -> PathBuf {
+        self.journal_path(filename, "tmp")
+    }
+
+    // The lock is kept next to the file, to be seen by the other users
+    // editing it, and not only by the ones sharing a state directory.
+    pub(crate) fn lock_path(&self, filename: &str) -> PathBuf {
+        let path = self.absolute_path(filename);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!(".{}.ned-lock", name))
+    }
+
+    // The journal of a read-only buffer belongs to the process editing it,
+    // and a buffer whose backup couldn't be written goes without one.
+    fn journal_file(&self, ext: &str) -> Option<PathBuf> {
+        match &self.filename {
+            Some(filename) if !self.read_only && self.journaled => Some(self.journal_path(filename, ext)),
+            _ => None
         }
     }
 
-    pub fn rm_log(&self) {
-        if let Some(filename) = self.filename.clone() {
-            let file = format!(".{}.ned", filename);
-            fs::remove_file(file).expect("Unable to remove ned file");
+    pub(crate) fn mk_log(&mut self) -> Result<(), Error> {
+        if let Some(file) = self.journal_file("ned") {
+            let data = Format::default().encode(&self.history)?;
+            self.fs.create_dir_all(&self.state_dir).map_err(|e| Error::Io(e.kind()))?;
+            self.fs.write(&file, &data).map_err(|e| Error::Io(e.kind()))?;
         }
+        Ok(())
     }
 
-    pub fn mk_tmp(&self) {
-        if let Some(filename) = self.filename.clone() {
-            let file = format!(".{}.tmp", filename);
-            let data = self.lines.join("\n") + "\n";
-            fs::write(file, data).expect("Unable to write tmp file");
+    pub(crate) fn rm_log(&self) {
+        if let Some(file) = self.journal_file("ned") {
+            self.fs.remove(&file).ok(); // It might never have been written
         }
     }
 
-    pub fn rm_tmp(&self) {
-        if let Some(filename) = self.filename.clone() {
-            let file = format!(".{}.tmp", filename);
-            fs::remove_file(file).expect("Unable to remove tmp file");
+    // A file mapped in memory is its own backup, to avoid copying it, which
+    // is why its journal is reset when it is written.
+    pub(crate) fn mk_tmp(&self) -> Result<(), Error> {
+        if self.lines.is_mapped() {
+            self.rm_tmp();
+            return Ok(());
+        }
+        if let Some(file) = self.journal_file("tmp") {
+            let data = self.format.encode(self.lines.as_ref())?;
+            self.fs.create_dir_all(&self.state_dir).map_err(|e| Error::Io(e.kind()))?;
+            self.fs.write(&file, &data).map_err(|e| Error::Io(e.kind()))?;
         }
+        Ok(())
     }
 
-    pub fn undo(&mut self) -> Result<State, Error> {
-        if let Some(_) = self.history.pop() {
-            let inputs = self.history.clone();
-            if let Some(filename) = self.filename.clone() {
-                let tmp = format!(".{}.tmp", filename);
-                if let Ok(lines) = read_lines(&tmp) {
-                    self.lines = lines; // Restore backup
-                    self.addr = self.lines.len();
-                    self.dirty = false;
-                    for input in inputs {
-                        self.exec(&input).ok();
+    pub(crate) fn rm_tmp(&self) {
+        if let Some(file) = self.journal_file("tmp") {
+            self.fs.remove(&file).ok();
+        }
+    }
+
+    // A file is still edited when its backup can't be written, like when
+    // the state directory is read-only, but without a journal.
+    pub(crate) fn start_journal(&mut self) {
+        self.journaled = true;
+        if let Err(error) = self.mk_tmp() {
+            self.rm_tmp();
+            self.journaled = false;
+            let kind = match error {
+                Error::Io(kind) => kind,
+                _ => io::ErrorKind::Other
+            };
+            self.print_error(Error::NoJournal(kind));
+        }
+    }
+
+    // The journal and the lock follow the buffer when its file is renamed,
+    // so the new file is protected and can be recovered after a crash.
+    pub(crate) fn rename(&mut self, filename: &str) -> Result<(), Error> {
+        let lock = self.try_lock(filename)?;
+        let old: Vec<_> = ["ned", "tmp"].iter().map(|ext| self.journal_file(ext)).collect();
+        self.filename = Some(filename.to_string());
+        let new: Vec<_> = ["ned", "tmp"].iter().map(|ext| self.journal_file(ext)).collect();
+        for (old, new) in old.into_iter().zip(new).filter(|(old, new)| old != new) {
+            if let Some(old) = old {
+                if let Some(new) = new {
+                    if self.fs.copy(&old, &new).is_err() { // It might never have been written
+                        self.fs.remove(&new).ok();
                     }
                 }
+                self.fs.remove(&old).ok();
             }
-            self.mk_log();
-            Ok(State::Running)
+        }
+        self.set_lock(lock);
+        Ok(())
+    }
+
+    // Files larger than `lazy_size` are mapped in memory instead of being
+    // read, and their format is guessed from their first line.
+    pub(crate) fn read_storage(&self, filename: &str) -> Result<(Box<dyn Storage>, Format), Error> {
+        let path = Path::new(filename);
+        let size = self.fs.size(path).map_err(|e| Error::CannotOpen(e.kind()))?;
+        if size > 0 && size >= self.lazy_size {
+            let data = self.fs.map(path).map_err(|e| Error::CannotOpen(e.kind()))?;
+            let bytes = (*data).as_ref();
+            let first = bytes.iter().position(|&b| b == b'\n').map_or(bytes, |i| &bytes[..=i]);
+            let mut format = Format::detect(first, Some(self.encoding.unwrap_or(Encoding::Utf8)));
+            format.final_newline = bytes.ends_with(b"\n");
+            Ok((Box::new(Lazy::new(data, format)), format))
         } else {
-            Err(Error::NoUndo)
+            let (lines, format) = read_file(self.fs.as_ref(), path, self.encoding)?;
+            Ok((Box::new(Rope::from(lines)), format))
         }
     }
+
+    // Remember what the file looks like on disk, to notice when it's changed
+    // by another program. Mapped files are not hashed to avoid reading them.
+    pub(crate) fn stamp_file(&mut self) {
+        self.stamp = self.filename.as_ref().and_then(|filename| {
+            let path = Path::new(filename);
+            let size = self.fs.size(path).ok()?;
+            let hash = if self.lines.is_mapped() { None } else { self.fs.read(path).ok().map(|data| hash(&data)) };
+            Some(Stamp { modified: self.fs.modified(path), size, hash })
+        });
+    }
+
+    // The file was changed on disk since it was read or written, unless it
+    // was only touched without changing its content.
+    pub(crate) fn is_modified(&self) -> bool {
+        let (filename, stamp) = match (&self.filename, &self.stamp) {
+            (Some(filename), Some(stamp)) => (filename, stamp),
+            _ => return false
+        };
+        let path = Path::new(filename);
+        let size = match self.fs.size(path) {
+            Ok(size) => size,
+            Err(_) => return false // The file was removed
+        };
+        if size != stamp.size {
+            return true;
+        }
+        let modified = self.fs.modified(path);
+        if modified.is_some() && modified == stamp.modified {
+            return false;
+        }
+        match stamp.hash {
+            Some(h) => self.fs.read(path).map_or(true, |data| hash(&data) != h),
+            None => modified != stamp.modified
+        }
+    }
+
+    // The process holding the lock of a file, which might not be running
+    // anymore, or 0 if it is still writing its pid.
+    fn lock_pid(&self, path: &Path) -> Option<u32> {
+        let data = self.fs.read(path).ok()?;
+        Some(String::from_utf8_lossy(&data).trim().parse::<u32>().unwrap_or(0))
+    }
+
+    // The process editing a file, if it's another ned still running
+    pub fn lock_owner(&self, filename: &str) -> Option<u32> {
+        let path = self.lock_path(filename);
+        if self.lock.as_ref() == Some(&path) {
+            return None;
+        }
+        match self.lock_pid(&path)? {
+            0 => Some(0),
+            pid if pid != process::id() && lock::is_running(pid) => Some(pid),
+            _ => None
+        }
+    }
+
+    // Take the lock of a file without releasing the one of the buffer, in
+    // case the file can't be opened.
+    pub(crate) fn take_lock(&self, filename: &str) -> Result<PathBuf, Error> {
+        let path = self.lock_path(filename);
+        if self.lock.as_ref() == Some(&path) {
+            return Ok(path);
+        }
+        if let Some(pid) = self.lock_owner(filename) {
+            return Err(Error::Locked(pid));
+        }
+        if self.lock_pid(&path).is_some() {
+            self.fs.remove(&path).ok(); // Left by a process that is not running
+        }
+        let data = format!("{}\n", process::id());
+        match self.fs.create(&path, data.as_bytes()) {
+            Ok(()) => Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(Error::Locked(self.lock_owner(filename).unwrap_or(0))),
+            Err(e) => Err(Error::Io(e.kind()))
+        }
+    }
+
+    // A read-only buffer doesn't lock its file, and neither does one whose
+    // lock can't be written, like its journal.
+    pub(crate) fn try_lock(&self, filename: &str) -> Result<Option<PathBuf>, Error> {
+        if self.read_only {
+            return Ok(None);
+        }
+        match self.take_lock(filename) {
+            Ok(path) => Ok(Some(path)),
+            Err(Error::Io(_)) => Ok(None),
+            Err(error) => Err(error)
+        }
+    }
+
+    pub(crate) fn set_lock(&mut self, path: Option<PathBuf>) {
+        if self.lock != path {
+            if let Some(lock) = self.lock.take() {
+                self.fs.remove(&lock).ok();
+            }
+            self.lock = path;
+            let locks: Vec<&Path> = self.lock.iter().chain(self.buffers.iter().flat_map(|b| &b.lock)).
+                map(|path| path.as_path()).collect();
+            lock::set_locks(&locks);
+        }
+    }
+
+    // The fields of the current buffer are swapped with the ones kept for
+    // buffer `i`, leaving an empty buffer in its place.
+    pub(crate) fn switch_buffer(&mut self, i: usize) {
+        if i == self.buffer || i >= self.buffers.len() {
+            return;
+        }
+        let mut current = mem::take(&mut self.buffers[self.buffer]);
+        self.swap_buffer(&mut current);
+        self.buffers[self.buffer] = current;
+        let mut next = mem::take(&mut self.buffers[i]);
+        self.swap_buffer(&mut next);
+        self.buffers[i] = next;
+        self.buffer = i;
+    }
+
+    fn swap_buffer(&mut self, buffer: &mut Buffer) {
+        mem::swap(&mut self.dirty, &mut buffer.dirty);
+        mem::swap(&mut self.read_only, &mut buffer.read_only);
+        mem::swap(&mut self.journaled, &mut buffer.journaled);
+        mem::swap(&mut self.filename, &mut buffer.filename);
+        mem::swap(&mut self.stamp, &mut buffer.stamp);
+        mem::swap(&mut self.format, &mut buffer.format);
+        mem::swap(&mut self.addr, &mut buffer.addr);
+        mem::swap(&mut self.lines, &mut buffer.lines);
+        mem::swap(&mut self.marks, &mut buffer.marks);
+        mem::swap(&mut self.history, &mut buffer.history);
+        mem::swap(&mut self.undo_tree, &mut buffer.undo_tree);
+        mem::swap(&mut self.lock, &mut buffer.lock);
+    }
+
+    // The destination of t and m, which can be in another buffer like "2:$"
+    pub(crate) fn parse_target(&mut self, params: &[String]) -> Result<(usize, usize), Error> {
+        let param = params.first().ok_or(Error::InvalidAddress)?;
+        let (buffer, addr) = match param.split_once(':') {
+            Some((n, addr)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+                let n = n.parse::<usize>().map_err(|_| Error::InvalidNumber)?;
+                if n == 0 || n > self.buffers.len() {
+                    return Err(Error::NoBuffer);
+                }
+                (n - 1, addr)
+            },
+            _ => (self.buffer, param.as_str())
+        };
+        let current = self.buffer;
+        self.switch_buffer(buffer);
+        let res = match addr {
+            "" => Ok(self.addr),
+            _ => self.parse_addr(addr)
+        };
+        let res = res.and_then(|addr| if addr <= self.lines.len() { Ok(addr) } else { Err(Error::InvalidAddress) });
+        self.switch_buffer(current);
+        res.map(|addr| (buffer, addr))
+    }
+
+    // Append lines to another buffer, as a change of its own undo tree
+    pub(crate) fn copy_to_buffer(&mut self, buffer: usize, addr_1: usize, addr_2: usize, dest: usize) -> Result<(), Error> {
+        let lines: Vec<String> = (addr_1 .. addr_2 + 1).map(|i| self.lines.get(i - 1).into_owned()).collect();
+        let (current, undo_addr) = (self.buffer, self.undo_addr);
+        self.switch_buffer(buffer);
+        if self.read_only {
+            self.switch_buffer(current);
+            return Err(Error::ReadOnly);
+        }
+        self.undo_addr = self.addr;
+        self.append_lines(dest, lines);
+        self.commit();
+        self.switch_buffer(current);
+        self.undo_addr = undo_addr;
+        Ok(())
+    }
+
+    // Insert lines after an address, journaled as if they had been typed
+    pub(crate) fn append_lines(&mut self, dest: usize, lines: Vec<String>) {
+        self.history.push(format!("{}a", dest));
+        self.history.extend_from_slice(&lines);
+        self.history.push(".".to_string());
+        self.mk_log().ok();
+        self.addr = dest + lines.len();
+        self.splice(dest, 0, lines);
+        self.dirty = true;
+    }
+
+    // The register named by the first param of y, pu, d, rec and @, if any
+    pub(crate) fn parse_register(&self, params: &[String]) -> Result<Option<char>, Error> {
+        match params.first().map(|p| p.as_bytes()) {
+            None => Ok(None),
+            Some(&[c]) if c.is_ascii_alphabetic() => Ok(Some(c as char)),
+            _ => Err(Error::InvalidCommand)
+        }
+    }
+
+    // The registers belong to the editor rather than to a buffer. Like in
+    // vi an uppercase name appends to a register, and the unnamed register
+    // always holds the last lines yanked or deleted.
+    pub(crate) fn set_register(&mut self, name: Option<char>, lines: Vec<String>) {
+        let lines = match name {
+            Some(c) if c.is_ascii_uppercase() => {
+                let register = self.registers.entry(c.to_ascii_lowercase()).or_default();
+                register.extend(lines);
+                register.clone()
+            },
+            Some(c) => {
+                self.registers.insert(c, lines.clone());
+                lines
+            },
+            None => lines
+        };
+        self.registers.insert('"', lines);
+    }
+
+    // The buffer editing a file, if it was already opened
+    pub(crate) fn find_buffer(&self, filename: &str) -> Option<usize> {
+        let path = Some(self.absolute_path(filename));
+        if self.filename.as_ref().map(|f| self.absolute_path(f)) == path {
+            return Some(self.buffer);
+        }
+        self.buffers.iter().position(|b| b.filename.as_ref().map(|f| self.absolute_path(f)) == path)
+    }
+
+    // Remove a lock taken for a file that couldn't be edited, unless it is
+    // the one already held by the buffer.
+    pub(crate) fn drop_lock(&self, lock: Option<PathBuf>) {
+        if let Some(lock) = lock.filter(|lock| self.lock.as_ref() != Some(lock)) {
+            self.fs.remove(&lock).ok();
+        }
+    }
+
+    // A journal left behind by a session that didn't quit properly
+    pub fn has_journal(&self, filename: &str) -> bool {
+        self.fs.exists(&self.log_path(filename))
+    }
+
+    // Replay the journal of a previous session onto the backup of the file
+    // it was editing, to get back the changes made before it crashed.
+    pub fn recover(&mut self, filename: &str) -> Result<State, Error> {
+        let (lines, format) = match read_file(self.fs.as_ref(), self.tmp_path(filename), self.encoding) {
+            Ok((lines, format)) => (Box::new(Rope::from(lines)) as Box<dyn Storage>, format),
+            Err(_) => self.read_storage(filename)? // There is no backup of mapped files
+        };
+        let inputs = read_lines(self.fs.as_ref(), self.log_path(filename))?;
+        let lock = self.try_lock(filename)?;
+        self.set_lock(lock);
+        self.filename = Some(filename.to_string());
+        self.journaled = true;
+        self.lines = lines;
+        self.format = format;
+        self.stamp = None; // The file might have changed since the crash
+        self.marks.clear();
+        self.addr = self.lines.len();
+        self.insert_mode = false;
+        self.history = Vec::new();
+        self.changes = Vec::new();
+        self.undo_tree = UndoTree::new(self.addr);
+        self.undo_tree.saved = None; // The backup might not be on disk anymore
+        for input in inputs {
+            self.exec(&input).ok();
+        }
+        self.dirty = true;
+        Ok(State::Running)
+    }
+
+    // Run the commands of a startup file, reporting the errors without
+    // stopping at them.
+    pub fn source(&mut self, path: &Path, silent: bool) -> Result<State, Error> {
+        for input in read_lines(self.fs.as_ref(), path)? {
+            match self.exec(input.trim_end()) {
+                Ok(State::Running) => {},
+                Ok(State::Stopped) => return Ok(State::Stopped),
+                Err(error) => {
+                    if !silent {
+                        self.print_error(error);
+                    }
+                }
+            }
+        }
+        if self.insert_mode {
+            self.exec(".").ok();
+        }
+        Ok(State::Running)
+    }
+
+    // A startup file found in the current directory is only run once it
+    // has been trusted, and again when its content changes.
+    pub fn is_trusted(&self, path: &Path) -> bool {
+        match (self.fs.read(path), self.fs.read(&self.trusted_path(path))) {
+            (Ok(data), Ok(trusted)) => data == trusted,
+            _ => false
+        }
+    }
+
+    // A copy of the trusted content of a file is kept to be compared with
+    // its current one, rather than a hash that could collide.
+    pub fn trust(&mut self, path: &Path) -> Result<(), Error> {
+        let data = self.fs.read(path).map_err(|e| Error::CannotOpen(e.kind()))?;
+        let file = self.trusted_path(path);
+        self.fs.create_dir_all(&self.state_dir.join("trusted")).map_err(|e| Error::Io(e.kind()))?;
+        self.fs.write(&file, &data).map_err(|e| Error::Io(e.kind()))
+    }
+
+    fn trusted_path(&self, path: &Path) -> PathBuf {
+        let path = env::current_dir().map_or(path.to_path_buf(), |dir| dir.join(path));
+        self.state_dir.join("trusted").join(escape_path(&self.fs.canonicalize(&path)))
+    }
+
+    // The value of an option of the set command
+    pub fn option(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "prompt" => format!("\"{}\"", self.prompt),
+            "help" => (if self.show_help { "on" } else { "off" }).to_string(),
+            "backup" => self.backup.name().to_string(),
+            "encoding" => self.encoding.map_or("auto", |encoding| encoding.name()).to_string(),
+            "lazy" => self.lazy_size.to_string(),
+            "statedir" => self.state_dir.display().to_string(),
+            "historyfile" => self.history_file.display().to_string(),
+            "color" => self.theme.mode().name().to_string(),
+            "errorcolor" => color_name(self.theme.error).to_string(),
+            "numbercolor" => color_name(self.theme.number).to_string(),
+            "linecolor" => color_name(self.theme.line).to_string(),
+            "matchcolor" => color_name(self.theme.matched).to_string(),
+            "promptcolor" => color_name(self.theme.prompt).to_string(),
+            _ => return None
+        };
+        Some(value)
+    }
+
+    // The values can be quoted to keep their spaces, like "> " for the prompt
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(value) => value,
+            None => value
+        };
+        match name {
+            "prompt" => {
+                self.prompt = value.to_string();
+            },
+            "help" => {
+                self.show_help = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(Error::InvalidOption)
+                };
+            },
+            "backup" => {
+                self.backup = Backup::parse(value).ok_or(Error::InvalidOption)?;
+            },
+            "encoding" => {
+                self.encoding = match value {
+                    "auto" => None,
+                    _ => Some(Encoding::parse(value).ok_or(Error::InvalidOption)?)
+                };
+            },
+            "lazy" => {
+                self.lazy_size = value.parse().map_err(|_| Error::InvalidOption)?;
+            },
+            "statedir" => {
+                self.state_dir = PathBuf::from(value);
+            },
+            "historyfile" => {
+                self.history_file = PathBuf::from(value);
+            },
+            "color" => {
+                self.theme.set_mode(ColorMode::parse(value).ok_or(Error::InvalidOption)?);
+            },
+            "errorcolor" | "numbercolor" | "linecolor" | "matchcolor" | "promptcolor" => {
+                let color = parse_color(value)?;
+                match name {
+                    "errorcolor" => self.theme.error = color,
+                    "numbercolor" => self.theme.number = color,
+                    "linecolor" => self.theme.line = color,
+                    "matchcolor" => self.theme.matched = color,
+                    _ => self.theme.prompt = color
+                }
+            },
+            _ => return Err(Error::InvalidOption)
+        }
+        Ok(())
+    }
+
+    pub(crate) fn undo(&mut self) -> Result<State, Error> {
+        match self.undo_tree.parent() {
+            Some(i) => {
+                let addr = self.undo_tree.states[self.undo_tree.current].addr_before;
+                self.restore(i, addr);
+                Ok(State::Running)
+            },
+            None => Err(Error::NoUndo)
+        }
+    }
+
+    pub(crate) fn redo(&mut self) -> Result<State, Error> {
+        match self.undo_tree.child() {
+            Some(i) => {
+                let addr = self.undo_tree.states[i].addr_after;
+                self.restore(i, addr);
+                Ok(State::Running)
+            },
+            None => Err(Error::NoRedo)
+        }
+    }
+
+    pub(crate) fn restore(&mut self, i: usize, addr: usize) {
+        self.undo_tree.goto_lines(i, self.lines.as_mut(), &mut self.marks);
+        self.addr = addr;
+        self.dirty = !self.undo_tree.is_saved();
+        self.history = self.undo_tree.inputs(i);
+        self.mk_log().ok();
+    }
 }
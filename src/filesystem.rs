@@ -0,0 +1,471 @@
+use memmap2::Mmap;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::time::SystemTime;
+use crate::utils::timestamp;
+
+// The content of a file that doesn't have to be copied in memory
+pub type Bytes = Rc<dyn AsRef<[u8]>>;
+
+// Every access to the files edited and to the journals goes through a
+// filesystem, which can be replaced to edit files that are not on disk.
+pub trait Filesystem: fmt::Debug {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    fn exists(&self, path: &Path) -> bool;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        self.read(path).map(|data| data.len() as u64)
+    }
+
+    fn modified(&self, _path: &Path) -> Option<SystemTime> {
+        None
+    }
+
+    fn map(&self, path: &Path) -> io::Result<Bytes> {
+        self.read(path).map(|data| Rc::new(data) as Bytes)
+    }
+
+    // Replace the content of a file the user is editing, which should never
+    // leave it half written.
+    fn save(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.write(path, data)
+    }
+
+    // Like save, with the content written by a function instead of being
+    // given at once.
+    fn save_with(&self, path: &Path, write: &mut dyn FnMut(&mut dyn Write) -> io::Result<()>) -> io::Result<()> {
+        let mut data = Vec::new();
+        write(&mut data)?;
+        self.save(path, &data)
+    }
+
+    // The same file is always given the same absolute path, to find its lock
+    // and journal whichever way it was named.
+    fn canonicalize(&self, path: &Path) -> PathBuf {
+        path.components().filter(|c| *c != Component::CurDir).collect()
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.read(from).and_then(|data| self.save(to, &data))
+    }
+
+    // Write a file that must not already exist
+    fn create(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        if self.exists(path) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        self.write(path, data)
+    }
+}
+
+// The state of a file on disk when it was last read or written
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stamp {
+    pub modified: Option<SystemTime>,
+    pub size: u64,
+    pub hash: Option<u64>,
+}
+
+// The copy of a file made before overwriting it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backup {
+    None,
+    Simple, // file~
+    Timestamped, // file.~20191231-235959~
+}
+
+impl Backup {
+    pub fn parse(name: &str) -> Option<Backup> {
+        match name {
+            "none" => Some(Backup::None),
+            "simple" => Some(Backup::Simple),
+            "timestamped" => Some(Backup::Timestamped),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Backup::None => "none",
+            Backup::Simple => "simple",
+            Backup::Timestamped => "timestamped",
+        }
+    }
+
+    pub fn path(&self, path: &Path) -> Option<PathBuf> {
+        let mut name = path.as_os_str().to_os_string();
+        match self {
+            Backup::None => return None,
+            Backup::Simple => name.push("~"),
+            Backup::Timestamped => name.push(format!(".~{}~", timestamp(SystemTime::now()))),
+        }
+        Some(PathBuf::from(name))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RealFs;
+
+impl Filesystem for RealFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        fs::write(path, data)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        fs::metadata(path).map(|metadata| metadata.len())
+    }
+
+    fn modified(&self, path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    // A file that doesn't exist yet is found from its directory
+    fn canonicalize(&self, path: &Path) -> PathBuf {
+        if let Ok(path) = fs::canonicalize(path) {
+            return path;
+        }
+        match (path.parent().map(fs::canonicalize), path.file_name()) {
+            (Some(Ok(dir)), Some(name)) => dir.join(name),
+            _ => path.to_path_buf()
+        }
+    }
+
+    // The data is written to a temporary file in the same directory, synced
+    // and renamed over the file, with the mode and owner of the file. The
+    // target of a symlink is replaced instead of the link itself.
+    fn save(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.save_with(path, &mut |out| out.write_all(data))
+    }
+
+    fn save_with(&self, path: &Path, write: &mut dyn FnMut(&mut dyn Write) -> io::Result<()>) -> io::Result<()> {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".ned-{}", process::id()));
+        let tmp = dir.join(name);
+
+        let res = (|| {
+            let file = fs::OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+            let mut out = io::BufWriter::new(file);
+            write(&mut out)?;
+            let file = out.into_inner().map_err(|e| e.into_error())?;
+            if let Ok(metadata) = fs::metadata(&path) {
+                file.set_permissions(metadata.permissions())?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::MetadataExt;
+                    std::os::unix::fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid())).ok();
+                }
+            }
+            file.sync_all()?;
+            fs::rename(&tmp, &path)
+        })();
+        if res.is_err() {
+            fs::remove_file(&tmp).ok();
+        }
+        res?;
+        if let Ok(dir) = fs::File::open(dir) {
+            dir.sync_all().ok(); // Make the rename durable
+        }
+        Ok(())
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::copy(from, to).map(|_| ())
+    }
+
+    fn create(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        fs::OpenOptions::new().write(true).create_new(true).open(path)?.write_all(data)
+    }
+
+    // The file is mapped in memory instead of being read, so only the pages
+    // that are accessed are loaded. It must not be truncated by another
+    // process while it is mapped.
+    fn map(&self, path: &Path) -> io::Result<Bytes> {
+        let file = fs::File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        Ok(Rc::new(mmap))
+    }
+}
+
+// Keep the files in memory. They are shared between clones, so one of them
+// can be given to the editor while the other is used to look at the files.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFs {
+    files: Rc<RefCell<HashMap<PathBuf, Vec<u8>>>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    pub fn insert<P: AsRef<Path>>(&self, path: P, data: &[u8]) {
+        self.files.borrow_mut().insert(path.as_ref().to_path_buf(), data.to_vec());
+    }
+
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        self.files.borrow().get(path.as_ref()).cloned()
+    }
+}
+
+impl Filesystem for MemoryFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.get(path).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.insert(path, data);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        match self.files.borrow_mut().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::from(io::ErrorKind::NotFound))
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.borrow().contains_key(path)
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::editor::*;
+
+    pub static TEST_FILE: &str = "LICENSE";
+    pub static TEST_FILE_LENGTH: usize = 21;
+    pub static TEST_DATA: &str = "The MIT License (MIT)
+
+Copyright (c) 2019 Vincent Ollivier
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the \"Software\"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED \"AS IS\", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+";
+
+    // An editor working on an in-memory filesystem containing the test file
+    pub fn test_editor() -> (Editor, MemoryFs) {
+        let fs = MemoryFs::new();
+        fs.insert(TEST_FILE, TEST_DATA.as_bytes());
+        let mut ed = Editor::new();
+        ed.fs = Box::new(fs.clone());
+        (ed, fs)
+    }
+
+    #[test]
+    fn test_memory_fs() {
+        let (mut ed, fs) = test_editor();
+        ed.exec(&format!("e {}", TEST_FILE)).ok();
+        assert_eq!(ed.lines.len(), TEST_FILE_LENGTH);
+        assert!(!ed.has_journal(TEST_FILE));
+        ed.exec("2,$d").ok();
+        assert!(ed.has_journal(TEST_FILE));
+        ed.exec("w").ok();
+        assert_eq!(fs.get(TEST_FILE), Some(b"The MIT License (MIT)\n".to_vec()));
+        ed.exec("q").ok();
+        assert!(!ed.has_journal(TEST_FILE));
+    }
+
+    #[test]
+    fn test_real_fs_save() {
+        let dir = std::env::temp_dir().join(format!("ned-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        RealFs.save(&file, b"a\n").unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"a\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{symlink, PermissionsExt};
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+            let link = dir.join("link");
+            symlink(&file, &link).unwrap();
+            RealFs.save(&link, b"b\n").unwrap();
+            assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+            assert_eq!(fs::read(&file).unwrap(), b"b\n");
+            assert_eq!(fs::metadata(&file).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), if cfg!(unix) { 2 } else { 1 });
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_no_journal() {
+        let dir = std::env::temp_dir().join(format!("ned-test-journal-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        fs::write(&file, b"a\nb\nc\n").unwrap();
+        let mut ed = Editor::new();
+        let output = crate::output::Capture::new();
+        ed.output = Box::new(output.clone());
+        ed.show_help = true;
+        ed.state_dir = file.join("state"); // Not a directory
+        let filename = file.to_string_lossy().to_string();
+        assert_eq!(ed.exec(&format!("e {}", filename)), Ok(State::Running));
+        assert_eq!(output.take_errors().len(), 1);
+        assert!(!ed.journaled);
+        assert_eq!(ed.exec("2d"), Ok(State::Running));
+        assert_eq!(ed.exec("w"), Ok(State::Running));
+        assert_eq!(fs::read(&file).unwrap(), b"a\nc\n");
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_lock() {
+        let (mut ed, fs) = test_editor();
+        let lock = ed.lock_path(TEST_FILE);
+        fs.insert(&lock, b"1\n"); // Always running
+        assert_eq!(ed.exec(&format!("e {}", TEST_FILE)), Err(Error::Locked(1)));
+        ed.read_only = true;
+        assert_eq!(ed.exec(&format!("e {}", TEST_FILE)), Ok(State::Running));
+        ed.exec("2,$d").ok();
+        assert!(!ed.has_journal(TEST_FILE));
+        assert_eq!(ed.exec("w"), Err(Error::ReadOnly));
+        assert_eq!(ed.exec("q!"), Ok(State::Stopped));
+        assert_eq!(fs.get(&lock), Some(b"1\n".to_vec()));
+
+        let (mut ed, fs) = test_editor();
+        fs.insert(&lock, b"999999999\n"); // Not running
+        assert_eq!(ed.exec(&format!("e {}", TEST_FILE)), Ok(State::Running));
+        assert_eq!(fs.get(&lock), Some(format!("{}\n", process::id()).into_bytes()));
+        assert_eq!(ed.exec("q"), Ok(State::Stopped));
+        assert_eq!(fs.get(&lock), None);
+
+        // The lock is the same whichever way the file is named, and one
+        // whose pid is not written yet is never removed.
+        let (mut ed, fs) = test_editor();
+        assert_eq!(ed.lock_path(&format!("./{}", TEST_FILE)), lock);
+        assert!(lock.ends_with(format!(".{}.ned-lock", TEST_FILE)));
+        fs.insert(&lock, b"");
+        assert_eq!(ed.exec(&format!("e ./{}", TEST_FILE)), Err(Error::Locked(0)));
+        assert_eq!(fs.get(&lock), Some(b"".to_vec()));
+
+        // The lock follows the buffer when its file is renamed
+        let (mut ed, fs) = test_editor();
+        fs.insert(ed.lock_path("locked"), b"1\n");
+        assert_eq!(ed.exec(&format!("e {}", TEST_FILE)), Ok(State::Running));
+        assert_eq!(ed.exec("w locked"), Err(Error::Locked(1)));
+        assert_eq!(ed.exec("f locked"), Err(Error::Locked(1)));
+        assert_eq!(ed.exec("f"), Ok(State::Running));
+        assert!(fs.exists(&lock));
+        assert_eq!(ed.exec("f other"), Ok(State::Running));
+        assert!(!fs.exists(&lock));
+        assert!(fs.exists(&ed.lock_path("other")));
+        assert_eq!(ed.exec("w g2"), Ok(State::Running));
+        assert!(!fs.exists(&ed.lock_path("other")));
+        assert_eq!(ed.exec("q"), Ok(State::Stopped));
+        assert!(!fs.exists(&ed.lock_path("g2")));
+    }
+
+    #[test]
+    fn test_recover() {
+        let (mut ed, fs) = test_editor();
+        ed.exec(&format!("e {}", TEST_FILE)).ok();
+        ed.exec("2,$d").ok();
+        ed.exec("a").ok();
+        ed.exec("Recovered").ok();
+        ed.exec(".").ok();
+        drop(ed); // Crash without quitting
+
+        let mut ed = Editor::new();
+        ed.fs = Box::new(fs.clone());
+        assert!(ed.has_journal(TEST_FILE));
+        assert_eq!(ed.exec(&format!("e {}", TEST_FILE)), Err(Error::Recoverable));
+        assert!(ed.has_journal(TEST_FILE));
+        assert_eq!(ed.exec(&format!("recover {}", TEST_FILE)), Ok(State::Running));
+        assert_eq!(ed.lines.to_vec(), vec!["The MIT License (MIT)", "Recovered"]);
+        assert!(ed.dirty);
+        drop(ed);
+
+        // The changes are discarded with e!
+        let mut ed = Editor::new();
+        ed.fs = Box::new(fs.clone());
+        assert_eq!(ed.exec(&format!("e! {}", TEST_FILE)), Ok(State::Running));
+        assert_eq!(ed.lines.len(), TEST_FILE_LENGTH);
+        assert!(!ed.has_journal(TEST_FILE));
+        drop(ed);
+
+        // Bytes that are not valid UTF-8 are journaled as they were read
+        let (mut ed, fs) = test_editor();
+        fs.insert("file", b"a\x80\n");
+        ed.exec("e file").ok();
+        ed.exec("a").ok();
+        ed.exec("b\u{10FEFE}").ok();
+        ed.exec(".").ok();
+        drop(ed);
+        let mut ed = Editor::new();
+        ed.fs = Box::new(fs.clone());
+        assert_eq!(ed.exec("recover file"), Ok(State::Running));
+        assert_eq!(ed.lines.to_vec(), vec!["a\u{10FE80}", "b\u{10FEFE}"]);
+
+        // The journal follows the buffer when its file is renamed
+        let (mut ed, fs) = test_editor();
+        ed.exec(&format!("e {}", TEST_FILE)).ok();
+        ed.exec("1d").ok();
+        assert_eq!(ed.exec("f other"), Ok(State::Running));
+        assert!(!ed.has_journal(TEST_FILE));
+        assert!(!fs.exists(&ed.tmp_path(TEST_FILE)));
+        assert!(ed.has_journal("other"));
+        ed.exec("2d").ok();
+        drop(ed);
+        let mut ed = Editor::new();
+        ed.fs = Box::new(fs.clone());
+        assert_eq!(ed.exec("recover other"), Ok(State::Running));
+        assert_eq!(ed.lines.len(), TEST_FILE_LENGTH - 2);
+        assert_eq!(ed.exec("w g2"), Ok(State::Running));
+        assert!(!ed.has_journal("other"));
+        assert_eq!(ed.exec("q"), Ok(State::Stopped));
+        assert!(!ed.has_journal("g2"));
+        assert!(!fs.exists(&ed.tmp_path("g2")));
+        assert_eq!(ed.exec(&format!("e {}", TEST_FILE)), Ok(State::Running));
+    }
+}
@@ -0,0 +1,234 @@
+use crate::editor::Error;
+use crate::storage::Storage;
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::str;
+
+// Bytes that are not valid UTF-8 are decoded to private use characters,
+// from U+10FE00 to U+10FEFF, and encoded back to the same bytes. Those
+// characters are themselves decoded byte by byte to stay unambiguous.
+const ESCAPE: u32 = 0x10FE00;
+
+fn escape(b: u8) -> char {
+    char::from_u32(ESCAPE + b as u32).unwrap()
+}
+
+fn unescape(c: char) -> Option<u8> {
+    let c = c as u32;
+    if (ESCAPE ..= ESCAPE + 0xFF).contains(&c) { Some((c - ESCAPE) as u8) } else { None }
+}
+
+fn push_escaped(text: &mut String, s: &str) {
+    for c in s.chars() {
+        if unescape(c).is_some() {
+            let mut buf = [0; 4];
+            text.extend(c.encode_utf8(&mut buf).bytes().map(escape));
+        } else {
+            text.push(c);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Latin1,
+}
+
+impl Encoding {
+    pub fn parse(name: &str) -> Option<Encoding> {
+        match name {
+            "utf8" | "utf-8" => Some(Encoding::Utf8),
+            "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf8",
+            Encoding::Latin1 => "latin1",
+        }
+    }
+
+    // A file that is not valid UTF-8 is assumed to be Latin-1 unless it
+    // contains C1 control characters, which are unlikely in a text.
+    pub fn detect(data: &[u8]) -> Encoding {
+        if str::from_utf8(data).is_err() && !data.iter().any(|&b| (0x80..0xA0).contains(&b)) {
+            Encoding::Latin1
+        } else {
+            Encoding::Utf8
+        }
+    }
+
+    pub fn decode<'a>(&self, data: &'a [u8]) -> Cow<'a, str> {
+        match self {
+            Encoding::Utf8 => {
+                if let Ok(s) = str::from_utf8(data) {
+                    if !s.chars().any(|c| unescape(c).is_some()) {
+                        return Cow::Borrowed(s);
+                    }
+                }
+                let mut text = String::with_capacity(data.len());
+                let mut rest = data;
+                loop {
+                    match str::from_utf8(rest) {
+                        Ok(s) => {
+                            push_escaped(&mut text, s);
+                            break;
+                        },
+                        Err(e) => {
+                            let (valid, invalid) = rest.split_at(e.valid_up_to());
+                            push_escaped(&mut text, str::from_utf8(valid).unwrap());
+                            let n = e.error_len().unwrap_or(invalid.len());
+                            text.extend(invalid[..n].iter().map(|&b| escape(b)));
+                            rest = &invalid[n..];
+                        }
+                    }
+                }
+                Cow::Owned(text)
+            },
+            Encoding::Latin1 => {
+                if data.is_ascii() {
+                    Cow::Borrowed(str::from_utf8(data).unwrap())
+                } else {
+                    Cow::Owned(data.iter().map(|&b| b as char).collect())
+                }
+            }
+        }
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(text.len());
+        for c in text.chars() {
+            match (unescape(c), self) {
+                (Some(b), _) => data.push(b),
+                (None, Encoding::Utf8) => {
+                    let mut buf = [0; 4];
+                    data.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                },
+                (None, Encoding::Latin1) if (c as u32) < 0x100 => data.push(c as u8),
+                (None, Encoding::Latin1) => return Err(Error::Io(io::ErrorKind::InvalidData))
+            }
+        }
+        Ok(data)
+    }
+}
+
+// The line ending of a file, kept to write it back the way it was read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ending {
+    Lf,
+    Crlf,
+}
+
+impl Ending {
+    pub fn parse(name: &str) -> Option<Ending> {
+        match name {
+            "lf" => Some(Ending::Lf),
+            "crlf" => Some(Ending::Crlf),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Ending::Lf => "lf",
+            Ending::Crlf => "crlf",
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Ending::Lf => "\n",
+            Ending::Crlf => "\r\n",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Format {
+    pub encoding: Encoding,
+    pub ending: Ending,
+    pub final_newline: bool,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format { encoding: Encoding::Utf8, ending: Ending::Lf, final_newline: true }
+    }
+}
+
+impl Format {
+    // A file ends its lines with CRLF only if all of them do, otherwise the
+    // carriage returns are kept in the lines to be written back.
+    pub fn detect(data: &[u8], encoding: Option<Encoding>) -> Format {
+        let lf = data.iter().filter(|&&b| b == b'\n').count();
+        let crlf = data.windows(2).filter(|w| w == b"\r\n").count();
+        Format {
+            encoding: encoding.unwrap_or_else(|| Encoding::detect(data)),
+            ending: if lf > 0 && crlf == lf { Ending::Crlf } else { Ending::Lf },
+            final_newline: data.is_empty() || data.ends_with(b"\n"),
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Vec<String> {
+        let text = self.encoding.decode(data);
+        if text.is_empty() {
+            return Vec::new();
+        }
+        let sep = self.ending.as_str();
+        let text = text.strip_suffix(sep).unwrap_or(&text);
+        text.split(sep).map(|line| line.to_string()).collect()
+    }
+
+    pub fn encode(&self, lines: &dyn Storage) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        self.write(lines, &mut data).map_err(|e| Error::Io(e.kind()))?;
+        Ok(data)
+    }
+
+    // Encode the lines one by one, to never hold a large file in memory
+    pub fn write(&self, lines: &dyn Storage, out: &mut dyn Write) -> io::Result<()> {
+        let n = lines.len();
+        for (i, line) in lines.iter().enumerate() {
+            let data = self.encoding.encode(&line).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+            out.write_all(&data)?;
+            if i + 1 < n || self.final_newline {
+                out.write_all(self.ending.as_str().as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        for data in &["", "\n", "a\nb\n", "a\r\nb\r\n", "a\r\nb\n", "a\nb", "a\r\n\r\nb"] {
+            let format = Format::detect(data.as_bytes(), None);
+            let lines = format.decode(data.as_bytes());
+            assert_eq!(format.encode(&lines), Ok(data.as_bytes().to_vec()));
+        }
+        assert_eq!(Format::detect(b"a\r\nb", None).ending, Ending::Crlf);
+        assert_eq!(Format::detect(b"a\r\nb\n", None).ending, Ending::Lf);
+        assert!(!Format::detect(b"a\nb", None).final_newline);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let data = b"caf\xe9 \xf4\x8f\xb8\x80 \x00\x9f\xff \xf0\x9f\x98";
+        let text = Encoding::Utf8.decode(data);
+        assert!(text.starts_with("caf\u{10FEE9} "));
+        assert_eq!(Encoding::Utf8.encode(&text), Ok(data.to_vec()));
+
+        assert_eq!(Encoding::detect(b"caf\xe9"), Encoding::Latin1);
+        assert_eq!(Encoding::detect(data), Encoding::Utf8);
+        assert_eq!(Encoding::Latin1.decode(b"caf\xe9"), "caf\u{e9}");
+        assert_eq!(Encoding::Latin1.encode("caf\u{e9}"), Ok(b"caf\xe9".to_vec()));
+        assert!(Encoding::Latin1.encode("\u{263a}").is_err());
+    }
+}
@@ -0,0 +1,33 @@
+//! Ned is the new standard text editor.
+//!
+//! The editor can be embedded by creating an `Editor` and giving it command
+//! lines with `exec`, as if they were typed by the user. It is the only way
+//! to run commands, because their addresses are checked there. What it
+//! prints goes through its output, which can be replaced with `set_output`
+//! by a `Capture` to get it back as data, or by any other implementation of
+//! the `Output` trait. The files can likewise be kept in a `MemoryFs` with
+//! `set_filesystem`.
+
+mod addresses;
+mod buffer;
+mod commands;
+mod editor;
+mod filesystem;
+mod format;
+mod lock;
+mod output;
+mod storage;
+mod theme;
+mod undo;
+mod utils;
+
+pub use crate::editor::{Editor, Error, State};
+pub use crate::filesystem::{Backup, Bytes, Filesystem, MemoryFs, RealFs};
+pub use crate::format::{Encoding, Ending, Format};
+pub use crate::lock::release_on_signals;
+pub use crate::output::{Capture, Output, Writer};
+pub use crate::storage::{Rope, Storage};
+pub use crate::theme::{ColorMode, Theme};
+pub use crate::undo::Change;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -0,0 +1,65 @@
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+// The lock files of the buffers, as a null terminated array of C strings
+// that can be removed from a signal handler.
+static LOCKS: AtomicPtr<*mut libc::c_char> = AtomicPtr::new(ptr::null_mut());
+
+// Another process holding a lock might have been killed without removing it
+#[cfg(unix)]
+pub fn is_running(pid: u32) -> bool {
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+pub fn is_running(_pid: u32) -> bool {
+    true
+}
+
+// The previous paths are leaked rather than freed, since the handler could
+// be reading them.
+#[cfg(unix)]
+pub fn set_locks(paths: &[&Path]) {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut locks: Vec<*mut libc::c_char> = paths.iter().
+        filter_map(|path| CString::new(path.as_os_str().as_bytes()).ok()).
+        map(CString::into_raw).collect();
+    locks.push(ptr::null_mut());
+    LOCKS.store(Box::leak(locks.into_boxed_slice()).as_mut_ptr(), Ordering::SeqCst);
+}
+
+#[cfg(not(unix))]
+pub fn set_locks(_paths: &[&Path]) {
+}
+
+#[cfg(unix)]
+extern "C" fn remove_lock(signal: libc::c_int) {
+    let mut lock = LOCKS.load(Ordering::SeqCst);
+    unsafe {
+        while !lock.is_null() && !(*lock).is_null() {
+            libc::unlink(*lock);
+            lock = lock.add(1);
+        }
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+// Remove the lock file when ned is killed, while keeping the journal to be
+// able to recover the changes.
+#[cfg(unix)]
+pub fn release_on_signals() {
+    for &signal in &[libc::SIGHUP, libc::SIGINT, libc::SIGQUIT, libc::SIGTERM] {
+        unsafe {
+            libc::signal(signal, remove_lock as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn release_on_signals() {
+}
@@ -1,61 +1,211 @@
-mod addresses;
-mod commands;
-mod editor;
-mod utils;
-
-use crate::editor::*;
-use crate::utils::*;
+use ned::{ColorMode, Editor, Error, State};
 
 use rustyline::error::ReadlineError;
 use std::env;
-
-static PROMPT: &str = "> ";
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal};
+use std::path::Path;
+use std::process;
 
 fn main() {
-    let home = std::env::var("HOME").unwrap();
-    let history = format!("{}/.ned_history", home);
-
+    ned::release_on_signals();
     let mut ed = Editor::new();
-    let mut prompt = PROMPT;
-    let args: Vec<String> = env::args().filter(|arg| {
-        if arg == "--debug" {
-            ed.show_debug = true;
+    ed.set_color_mode(ColorMode::Auto);
+    let mut recover = false;
+    let mut silent = false;
+    let mut script = None;
+    let mut startup = true;
+    let mut options = vec![]; // Set after the startup files to override them
+    let mut args = vec![];
+    let mut env_args = env::args();
+    while let Some(arg) = env_args.next() {
+        match arg.as_str() {
+            "--debug" => {
+                ed.set_show_debug(true);
+            },
+            "--recover" => {
+                recover = true;
+            },
+            "-R" | "--read-only" => {
+                ed.set_read_only(true);
+            },
+            "--lazy" => {
+                options.push("set lazy=0".to_string());
+            },
+            "--norc" => {
+                startup = false;
+            },
+            "-s" | "--silent" => {
+                ed.set_show_prompt(false);
+                silent = true;
+            },
+            "-f" | "--file" => {
+                script = env_args.next();
+            },
+            "--encoding" => {
+                options.push(format!("set encoding={}", env_args.next().unwrap_or_default()));
+            },
+            "-p" | "--prompt" => {
+                if let Some(prompt) = env_args.next() {
+                    options.push(format!("set prompt=\"{}\"", prompt));
+                    ed.set_show_prompt(true);
+                }
+            },
+            _ => {
+                if let Some(dir) = arg.strip_prefix("--state-dir=") {
+                    options.push(format!("set statedir={}", dir));
+                } else if let Some(mode) = arg.strip_prefix("--color=") {
+                    options.push(format!("set color={}", mode));
+                } else if let Some(name) = arg.strip_prefix("--backup=") {
+                    options.push(format!("set backup={}", name));
+                } else if !arg.starts_with("--") {
+                    args.push(arg);
+                }
+            }
         }
-        if arg == "--silent" {
-            ed.show_help = false;
-            prompt = "";
+    }
+
+    let interactive = script.is_none() && io::stdin().is_terminal();
+    if startup {
+        run_startup_files(&mut ed, interactive, silent);
+    }
+    for option in options {
+        if let Err(error) = ed.exec(&option) {
+            ed.print_error(error.clone());
+            process::exit(error.exit_code());
+        }
+    }
+
+    let filename = args.get(1).cloned();
+    let code = if let Some(path) = script {
+        match File::open(&path) {
+            Ok(file) => run_script(ed, filename, recover, BufReader::new(file), silent),
+            Err(e) => {
+                let error = Error::CannotOpen(e.kind());
+                if !silent {
+                    ed.print_error(error.clone());
+                }
+                error.exit_code()
+            }
         }
-        !arg.starts_with("--")
-    }).collect();
+    } else if !interactive {
+        run_script(ed, filename, recover, io::stdin().lock(), silent)
+    } else {
+        run_interactive(ed, filename, recover)
+    };
+    process::exit(code);
+}
 
-    if args.len() == 2 {
-        let res = ed.exec(&format!("e {}", args[1]));
+// The startup file of the user is run before the one of the current
+// directory, which needs to be trusted first.
+fn run_startup_files(ed: &mut Editor, interactive: bool, silent: bool) {
+    let global = ed.config_file();
+    if global.exists() {
+        ed.source(&global, silent).ok();
+    }
+    let local = Path::new(".nedrc");
+    let is_global = local.canonicalize().ok() == global.canonicalize().ok();
+    if !local.exists() || is_global {
+        return;
+    }
+    if !ed.is_trusted(local) && interactive {
+        let mut rl = rustyline::Editor::<()>::new();
+        let question = "Run the commands of '.nedrc' in the current directory? (y/n) ";
+        if let Ok(answer) = rl.readline(question) {
+            if answer.trim() == "y" {
+                ed.trust(local).ok();
+            }
+        }
+    }
+    if ed.is_trusted(local) {
+        ed.source(local, silent).ok();
+    }
+}
 
-        if let Err(e) = res {
-            print_error(e, ed.show_help);
+// Read commands from a script or from stdin when it is not a terminal, and
+// stop at the first error like ed.
+fn run_script<R: BufRead>(mut ed: Editor, filename: Option<String>, recover: bool, reader: R, silent: bool) -> i32 {
+    ed.set_show_prompt(false);
+    if let Some(filename) = filename {
+        if let Err(error) = open(&mut ed, &filename, Some(true).filter(|_| recover)) {
+            if !silent {
+                ed.print_error(error.clone());
+            }
+            return error.exit_code();
+        }
+    }
+    let mut inputs = reader.lines();
+    loop {
+        let input = match inputs.next() {
+            Some(Ok(input)) => input,
+            Some(Err(e)) => return Error::Io(e.kind()).exit_code(),
+            None if ed.insert_mode() => ".".to_string(), // Leave insert mode like ed
+            None => "q".to_string() // Quit at the end of the script
+        };
+        match ed.exec(input.trim_end()) {
+            Err(error) => {
+                if !silent {
+                    ed.print_error(error.clone());
+                }
+                return error.exit_code();
+            },
+            Ok(State::Stopped) => {
+                return 0;
+            },
+            Ok(State::Running) => {
+            }
         }
     }
+}
+
+fn run_interactive(mut ed: Editor, filename: Option<String>, recover: bool) -> i32 {
+    let history = ed.history_file().to_path_buf();
 
     let mut rl = rustyline::Editor::<()>::new();
     rl.load_history(&history).ok();
 
+    if let Some(filename) = filename {
+        if let Some(pid) = ed.lock_owner(&filename).filter(|_| !ed.read_only()) {
+            let question = format!("'{}' is being edited by process {}. Open read-only? (y/n) ", filename, pid);
+            if let Ok(answer) = rl.readline(&question) {
+                ed.set_read_only(answer.trim() == "y");
+            }
+        }
+        let mut recover = Some(true).filter(|_| recover);
+        if ed.has_journal(&filename) && recover.is_none() && !ed.read_only() {
+            let question = format!("Recover unsaved changes to '{}'? (y/n) ", filename);
+            if let Ok(answer) = rl.readline(&question) {
+                recover = Some(answer.trim() == "y");
+            }
+        }
+        if let Err(e) = open(&mut ed, &filename, recover) {
+            ed.print_error(e);
+        }
+    }
+
+    let mut code = 0;
     loop {
-        let readline = rl.readline(if ed.insert_mode { "" } else { prompt });
+        let readline = rl.readline(&ed.render_prompt());
         match readline {
-            Err(ReadlineError::Interrupted) => {
-                break
-            },
-            Err(ReadlineError::Eof) => {
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
+                // Keep the journal if there are unsaved changes
+                if ed.insert_mode() {
+                    ed.exec(".").ok();
+                }
+                if let Err(error) = ed.exec("q") {
+                    code = error.exit_code();
+                }
                 break
             },
             Err(err) => {
                 println!("Error: {:?}", err);
+                code = 1;
                 break
             },
             Ok(input) => {
                 match ed.exec(input.trim_end()) {
                     Err(error) => {
-                        print_error(error, ed.show_help)
+                        ed.print_error(error)
                     },
                     Ok(State::Stopped) => {
                         rl.add_history_entry(input);
@@ -68,5 +218,44 @@ fn main() {
             }
         }
     }
-    rl.save_history(&history).unwrap();
+    rl.save_history(&history).ok();
+    code
+}
+
+// A journal left by a previous session is recovered, discarded when the
+// user declined to recover it, or makes the file fail to open.
+fn open(ed: &mut Editor, filename: &str, recover: Option<bool>) -> Result<State, Error> {
+    match recover {
+        Some(true) if ed.has_journal(filename) => ed.recover(filename),
+        Some(false) => ed.exec(&format!("e! {}", filename)),
+        _ => ed.exec(&format!("e {}", filename))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ned::{Capture, MemoryFs};
+    use std::io::Cursor;
+
+    fn run(fs: &MemoryFs, script: &str) -> i32 {
+        let mut ed = Editor::new();
+        ed.set_filesystem(Box::new(fs.clone()));
+        ed.set_output(Box::new(Capture::new()));
+        run_script(ed, Some("file".to_string()), false, Cursor::new(script), true)
+    }
+
+    #[test]
+    fn test_run_script() {
+        let fs = MemoryFs::new();
+        fs.insert("file", b"a\n");
+        assert_eq!(run(&fs, "$a\nb\n.\nw\n"), 0);
+        assert_eq!(fs.get("file"), Some(b"a\nb\n".to_vec()));
+
+        // A script ending in insert mode leaves it before quitting, keeping
+        // the journal of the unsaved line.
+        assert_eq!(run(&fs, "$a\nc\nw\n"), Error::Dirty.exit_code());
+        assert_eq!(fs.get("file"), Some(b"a\nb\n".to_vec()));
+        assert_eq!(run(&fs, "q\n"), Error::Recoverable.exit_code());
+    }
 }
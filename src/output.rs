@@ -0,0 +1,80 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+// Everything printed by the editor goes through an output, which can be
+// replaced to capture it or to render it differently.
+pub trait Output: fmt::Debug {
+    fn print(&mut self, line: &str);
+
+    fn print_error(&mut self, line: &str) {
+        self.print(line);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stdout;
+
+impl Output for Stdout {
+    fn print(&mut self, line: &str) {
+        println!("{}", line);
+    }
+}
+
+// Write the output to anything implementing `Write`, like a file or a
+// socket.
+pub struct Writer<W: Write> {
+    pub writer: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(writer: W) -> Writer<W> {
+        Writer { writer }
+    }
+}
+
+impl<W: Write> fmt::Debug for Writer<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Writer")
+    }
+}
+
+impl<W: Write> Output for Writer<W> {
+    fn print(&mut self, line: &str) {
+        writeln!(self.writer, "{}", line).ok();
+    }
+}
+
+// Keep the output in memory. The lines are shared between clones, so one
+// of them can be given to the editor while the other is used to read what
+// was printed.
+#[derive(Clone, Debug, Default)]
+pub struct Capture {
+    lines: Rc<RefCell<Vec<String>>>,
+    errors: Rc<RefCell<Vec<String>>>,
+}
+
+impl Capture {
+    pub fn new() -> Capture {
+        Capture::default()
+    }
+
+    pub fn take_lines(&self) -> Vec<String> {
+        self.lines.borrow_mut().drain(..).collect()
+    }
+
+    pub fn take_errors(&self) -> Vec<String> {
+        self.errors.borrow_mut().drain(..).collect()
+    }
+}
+
+impl Output for Capture {
+    fn print(&mut self, line: &str) {
+        self.lines.borrow_mut().push(line.to_string());
+    }
+
+    fn print_error(&mut self, line: &str) {
+        self.errors.borrow_mut().push(line.to_string());
+    }
+}
@@ -0,0 +1,391 @@
+use crate::filesystem::Bytes;
+use crate::format::{Ending, Format};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt;
+
+// The lines of the buffer are kept in a storage, which only needs to be
+// able to get a line and to replace a range of lines by other ones.
+pub trait Storage: fmt::Debug {
+    fn len(&self) -> usize;
+    fn get(&self, i: usize) -> Cow<'_, str>;
+
+    // Replace `n` lines at index `at` with the `new` ones and return the
+    // lines that were replaced.
+    fn splice(&mut self, at: usize, n: usize, new: Vec<String>) -> Vec<String>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Cow<'_, str>> + '_> {
+        Box::new((0..self.len()).map(move |i| self.get(i)))
+    }
+
+    fn join(&self, sep: &str) -> String {
+        let mut res = String::new();
+        for (i, line) in self.iter().enumerate() {
+            if i > 0 {
+                res.push_str(sep);
+            }
+            res.push_str(&line);
+        }
+        res
+    }
+
+    fn to_vec(&self) -> Vec<String> {
+        self.iter().map(|line| line.into_owned()).collect()
+    }
+
+    // The lines are read from a file mapped in memory
+    fn is_mapped(&self) -> bool {
+        false
+    }
+}
+
+impl Storage for Vec<String> {
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn get(&self, i: usize) -> Cow<'_, str> {
+        Cow::Borrowed(&self[i])
+    }
+
+    fn splice(&mut self, at: usize, n: usize, new: Vec<String>) -> Vec<String> {
+        Vec::splice(self, at .. at + n, new).collect()
+    }
+}
+
+const CHUNK_SIZE: usize = 1024;
+
+// A rope of lines kept in chunks of a bounded size, so that inserting or
+// removing lines anywhere in the buffer only moves the lines of a chunk and
+// updates the index of the first line of each chunk, instead of moving all
+// the lines that follow like in a vector.
+#[derive(Clone, Debug, Default)]
+pub struct Rope {
+    chunks: Vec<Vec<String>>,
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl Rope {
+    pub fn new() -> Rope {
+        Rope::default()
+    }
+
+    // Find the chunk containing the line at index `i`, or the end of the
+    // last chunk if `i` is the length of the rope.
+    fn locate(&self, i: usize) -> (usize, usize) {
+        if self.chunks.is_empty() {
+            return (0, 0);
+        }
+        let c = match self.starts.binary_search(&i) {
+            Ok(c) => c,
+            Err(c) => c - 1,
+        };
+        (c, i - self.starts[c])
+    }
+
+    fn update_starts(&mut self, from: usize) {
+        self.starts.truncate(from);
+        let mut start = match from {
+            0 => 0,
+            _ => self.starts[from - 1] + self.chunks[from - 1].len(),
+        };
+        for chunk in &self.chunks[from..] {
+            self.starts.push(start);
+            start += chunk.len();
+        }
+        self.len = start;
+    }
+}
+
+impl From<Vec<String>> for Rope {
+    fn from(lines: Vec<String>) -> Rope {
+        let mut rope = Rope::new();
+        rope.splice(0, 0, lines);
+        rope
+    }
+}
+
+impl Storage for Rope {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, i: usize) -> Cow<'_, str> {
+        let (c, j) = self.locate(i);
+        Cow::Borrowed(&self.chunks[c][j])
+    }
+
+    fn splice(&mut self, at: usize, n: usize, new: Vec<String>) -> Vec<String> {
+        if self.chunks.is_empty() {
+            self.chunks.push(Vec::new());
+            self.starts.push(0);
+        }
+        let (first, offset) = self.locate(at);
+
+        // Remove the lines from as many chunks as needed, dropping the ones
+        // left empty after the first chunk.
+        let mut old = Vec::with_capacity(n);
+        let (mut c, mut j) = (first, offset);
+        while old.len() < n {
+            let m = (n - old.len()).min(self.chunks[c].len() - j);
+            old.extend(self.chunks[c].drain(j .. j + m));
+            c += 1;
+            j = 0;
+        }
+        let end = c.max(first + 1);
+        let rest: Vec<Vec<String>> = self.chunks.drain(first + 1 .. end).filter(|chunk| !chunk.is_empty()).collect();
+        self.chunks.splice(first + 1 .. first + 1, rest);
+
+        // Insert the new lines in the first chunk, and split it if needed
+        let chunk = &mut self.chunks[first];
+        let tail = chunk.split_off(offset);
+        chunk.extend(new);
+        chunk.extend(tail);
+        if chunk.len() > 2 * CHUNK_SIZE {
+            let mut rest = chunk.split_off(CHUNK_SIZE).into_iter();
+            chunk.shrink_to_fit();
+            let mut chunks = Vec::new();
+            while rest.len() > 0 {
+                chunks.push(rest.by_ref().take(CHUNK_SIZE).collect());
+            }
+            self.chunks.splice(first + 1 .. first + 1, chunks);
+        } else if chunk.is_empty() && self.chunks.len() > 1 {
+            self.chunks.remove(first);
+        }
+        self.update_starts(first);
+        old
+    }
+}
+
+// The lines of a file that hasn't been read, found by looking for newlines
+// in its content only when they are needed.
+struct Original {
+    data: Bytes,
+    format: Format,
+    ends: RefCell<Vec<usize>>, // Position after the end of each line found
+}
+
+impl Original {
+    fn bytes(&self) -> &[u8] {
+        (*self.data).as_ref()
+    }
+
+    // Find the lines of the file up to the one at index `i`, or all of them,
+    // and return how many are known.
+    fn index(&self, i: usize) -> usize {
+        let data = self.bytes();
+        let mut ends = self.ends.borrow_mut();
+        let mut pos = ends.last().cloned().unwrap_or(0);
+        while ends.len() <= i && pos < data.len() {
+            pos = match data[pos..].iter().position(|&b| b == b'\n') {
+                Some(j) => pos + j + 1,
+                None => data.len()
+            };
+            ends.push(pos);
+        }
+        ends.len()
+    }
+
+    fn len(&self) -> usize {
+        self.index(usize::MAX)
+    }
+
+    fn get(&self, i: usize) -> Cow<'_, str> {
+        self.index(i);
+        let (start, end) = {
+            let ends = self.ends.borrow();
+            (if i > 0 { ends[i - 1] } else { 0 }, ends[i])
+        };
+        let line = &self.bytes()[start .. end];
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = match self.format.ending {
+            Ending::Crlf => line.strip_suffix(b"\r").unwrap_or(line),
+            Ending::Lf => line,
+        };
+        self.format.encoding.decode(line)
+    }
+}
+
+// A range of lines of the original file, or lines added to the buffer
+#[derive(Clone, Debug)]
+enum Piece {
+    Original(usize, usize),
+    Added(Vec<String>),
+}
+
+impl Piece {
+    fn len(&self) -> usize {
+        match self {
+            Piece::Original(_, n) => *n,
+            Piece::Added(lines) => lines.len(),
+        }
+    }
+}
+
+// A piece table over a file mapped in memory. The lines of the file are
+// only copied when they are changed, so opening a huge file to print or
+// search a few of its lines doesn't load all of it.
+pub struct Lazy {
+    original: Original,
+    pieces: Option<Vec<Piece>>, // Not split until the first change
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl Lazy {
+    pub fn new(data: Bytes, format: Format) -> Lazy {
+        let original = Original { data, format, ends: RefCell::new(Vec::new()) };
+        Lazy { original, pieces: None, starts: Vec::new(), len: 0 }
+    }
+
+    // Find the piece containing the line at index `i`
+    fn locate(&self, i: usize) -> (usize, usize) {
+        let k = match self.starts.binary_search(&i) {
+            Ok(k) => k,
+            Err(k) => k - 1,
+        };
+        (k, i - self.starts[k])
+    }
+
+    // Split the pieces so that one of them starts at index `i` and return it
+    fn split(pieces: &mut Vec<Piece>, i: usize) -> usize {
+        let mut start = 0;
+        for k in 0..pieces.len() {
+            let n = pieces[k].len();
+            if i == start {
+                return k;
+            }
+            if i < start + n {
+                let j = i - start;
+                let rest = match &mut pieces[k] {
+                    Piece::Original(first, n) => {
+                        let rest = Piece::Original(*first + j, *n - j);
+                        *n = j;
+                        rest
+                    },
+                    Piece::Added(lines) => Piece::Added(lines.split_off(j)),
+                };
+                pieces.insert(k + 1, rest);
+                return k + 1;
+            }
+            start += n;
+        }
+        pieces.len()
+    }
+
+    fn update_starts(&mut self) {
+        self.starts.clear();
+        self.len = 0;
+        for piece in self.pieces.iter().flatten() {
+            self.starts.push(self.len);
+            self.len += piece.len();
+        }
+    }
+}
+
+impl fmt::Debug for Lazy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lazy").field("pieces", &self.pieces).finish()
+    }
+}
+
+impl Storage for Lazy {
+    fn len(&self) -> usize {
+        match self.pieces {
+            Some(_) => self.len,
+            None => self.original.len(),
+        }
+    }
+
+    fn is_mapped(&self) -> bool {
+        true
+    }
+
+    fn get(&self, i: usize) -> Cow<'_, str> {
+        let pieces = match &self.pieces {
+            Some(pieces) => pieces,
+            None => return self.original.get(i),
+        };
+        let (k, j) = self.locate(i);
+        match &pieces[k] {
+            Piece::Original(first, _) => self.original.get(first + j),
+            Piece::Added(lines) => Cow::Borrowed(&lines[j]),
+        }
+    }
+
+    fn splice(&mut self, at: usize, n: usize, new: Vec<String>) -> Vec<String> {
+        let original = &self.original;
+        let pieces = self.pieces.get_or_insert_with(|| {
+            match original.len() {
+                0 => vec![],
+                n => vec![Piece::Original(0, n)],
+            }
+        });
+        let i = Lazy::split(pieces, at);
+        let j = Lazy::split(pieces, at + n);
+        let mut old = Vec::with_capacity(n);
+        for piece in pieces.drain(i .. j) {
+            match piece {
+                Piece::Original(first, n) => {
+                    old.extend((first .. first + n).map(|k| original.get(k).into_owned()));
+                },
+                Piece::Added(lines) => old.extend(lines),
+            }
+        }
+        if !new.is_empty() {
+            match i.checked_sub(1).map(|k| &mut pieces[k]) {
+                Some(Piece::Added(lines)) => lines.extend(new), // Merge with previous lines
+                _ => pieces.insert(i, Piece::Added(new)),
+            }
+        }
+        self.update_starts();
+        old
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_rope() {
+        let lines: Vec<String> = (0..5000).map(|i| i.to_string()).collect();
+        let mut rope = Rope::from(lines.clone());
+        let mut vec = lines;
+        assert_eq!(rope.len(), 5000);
+        assert!(rope.chunks.len() > 1);
+
+        let ops = [(0, 10, 3), (1020, 2000, 1), (2000, 0, 3000), (5990, 4, 0), (3, 5000, 7)];
+        for &(at, n, m) in ops.iter() {
+            let new: Vec<String> = (0..m).map(|i| format!("new {}", i)).collect();
+            assert_eq!(rope.splice(at, n, new.clone()), Storage::splice(&mut vec, at, n, new));
+            assert_eq!(rope.to_vec(), vec);
+        }
+        assert_eq!(rope.splice(0, rope.len(), vec![]).len(), vec.len());
+        assert!(rope.is_empty());
+    }
+
+    #[test]
+    fn test_lazy() {
+        let data = b"first\nsecond\r\nthird\n".to_vec();
+        let format = Format { ending: Ending::Crlf, ..Format::default() };
+        let mut lazy = Lazy::new(Rc::new(data), format);
+        assert_eq!(lazy.get(1), "second");
+        assert_eq!(lazy.original.ends.borrow().len(), 2); // Not indexed further
+        assert_eq!(lazy.len(), 3);
+
+        let mut vec = lazy.to_vec();
+        let ops = [(1, 0, 2), (0, 2, 1), (3, 1, 0), (1, 2, 3), (4, 0, 1)];
+        for &(at, n, m) in ops.iter() {
+            let new: Vec<String> = (0..m).map(|i| format!("new {}", i)).collect();
+            assert_eq!(lazy.splice(at, n, new.clone()), Storage::splice(&mut vec, at, n, new));
+            assert_eq!(lazy.to_vec(), vec);
+        }
+    }
+}
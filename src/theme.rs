@@ -0,0 +1,160 @@
+use crate::editor::Error;
+use colored::Color;
+use regex::Regex;
+use std::env;
+use std::io::{self, IsTerminal};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorMode {
+    Auto,
+    Always,
+    Never
+}
+
+impl ColorMode {
+    pub fn parse(name: &str) -> Option<ColorMode> {
+        match name {
+            "auto" => Some(ColorMode::Auto),
+            "always" => Some(ColorMode::Always),
+            "never" => Some(ColorMode::Never),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            ColorMode::Auto => "auto",
+            ColorMode::Always => "always",
+            ColorMode::Never => "never",
+        }
+    }
+}
+
+// The colors of the output, which are only used when the mode allows it.
+// Each of them can be "none" to leave a part of the output uncolored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Theme {
+    mode: ColorMode,
+    enabled: bool,
+    pub error: Option<Color>,
+    pub number: Option<Color>,
+    pub line: Option<Color>,
+    pub matched: Option<Color>,
+    pub prompt: Option<Color>,
+}
+
+// Without a terminal to check, like in tests, the editor doesn't use colors
+// until it is told to.
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            mode: ColorMode::Never,
+            enabled: false,
+            error: Some(Color::Red),
+            number: Some(Color::Yellow),
+            line: None,
+            matched: Some(Color::Cyan),
+            prompt: None,
+        }
+    }
+}
+
+impl Theme {
+    pub fn mode(&self) -> ColorMode {
+        self.mode
+    }
+
+    // In auto mode the colors are used when stdout is a terminal, unless
+    // NO_COLOR is set (https://no-color.org).
+    pub fn set_mode(&mut self, mode: ColorMode) {
+        self.mode = mode;
+        self.enabled = match mode {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => {
+                env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && io::stdout().is_terminal()
+            }
+        };
+    }
+
+    pub fn paint(&self, text: &str, color: Option<Color>) -> String {
+        match color {
+            Some(color) if self.enabled => format!("\x1b[{}m{}\x1b[0m", color.to_fg_str(), text),
+            _ => text.to_string()
+        }
+    }
+
+    pub fn paint_error(&self, text: &str) -> String {
+        match self.error {
+            Some(color) if self.enabled => format!("\x1b[1;{}m{}\x1b[0m", color.to_fg_str(), text),
+            _ => text.to_string()
+        }
+    }
+
+    pub fn paint_matches(&self, text: &str, re: &Regex) -> String {
+        if !self.enabled || self.matched.is_none() {
+            return text.to_string();
+        }
+        let mut res = String::new();
+        let mut i = 0;
+        for m in re.find_iter(text).filter(|m| !m.as_str().is_empty()) {
+            res.push_str(&text[i..m.start()]);
+            res.push_str(&self.paint(m.as_str(), self.matched));
+            i = m.end();
+        }
+        res.push_str(&text[i..]);
+        res
+    }
+}
+
+pub fn parse_color(name: &str) -> Result<Option<Color>, Error> {
+    match name {
+        "none" => Ok(None),
+        _ => name.replace('_', " ").parse().map(Some).map_err(|_| Error::InvalidOption)
+    }
+}
+
+pub fn color_name(color: Option<Color>) -> &'static str {
+    match color {
+        None => "none",
+        Some(Color::Black) => "black",
+        Some(Color::Red) => "red",
+        Some(Color::Green) => "green",
+        Some(Color::Yellow) => "yellow",
+        Some(Color::Blue) => "blue",
+        Some(Color::Magenta) => "magenta",
+        Some(Color::Cyan) => "cyan",
+        Some(Color::White) => "white",
+        Some(Color::BrightBlack) => "bright_black",
+        Some(Color::BrightRed) => "bright_red",
+        Some(Color::BrightGreen) => "bright_green",
+        Some(Color::BrightYellow) => "bright_yellow",
+        Some(Color::BrightBlue) => "bright_blue",
+        Some(Color::BrightMagenta) => "bright_magenta",
+        Some(Color::BrightCyan) => "bright_cyan",
+        Some(Color::BrightWhite) => "bright_white",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_theme() {
+        let mut theme = Theme::default();
+        let re = Regex::new("b+").unwrap();
+        assert_eq!(theme.paint_error("?"), "?");
+        assert_eq!(theme.paint_matches("abba", &re), "abba");
+
+        theme.set_mode(ColorMode::Always);
+        assert_eq!(theme.paint_error("?"), "\x1b[1;31m?\x1b[0m");
+        assert_eq!(theme.paint_matches("abba", &re), "a\x1b[36mbb\x1b[0ma");
+        assert_eq!(theme.paint("1", theme.line), "1");
+
+        assert_eq!(parse_color("bright_red"), Ok(Some(Color::BrightRed)));
+        assert_eq!(parse_color("none"), Ok(None));
+        assert_eq!(parse_color("pink"), Err(Error::InvalidOption));
+        assert_eq!(color_name(Some(Color::BrightRed)), "bright_red");
+    }
+}
@@ -0,0 +1,163 @@
+use crate::storage::Storage;
+use std::collections::BTreeMap;
+
+// Every change made to the buffer creates a new state in the undo tree, so
+// that undoing a change and then making another one doesn't lose the first
+// branch: it can still be reached by jumping to any of its states.
+//
+// Each state keeps the changes made to the lines of the buffer, so moving
+// between states only touches the lines that were changed instead of
+// replaying every command from the backup of the file.
+
+// Replace the `old` lines at index `at` with the `new` ones
+#[derive(Clone, Debug)]
+pub struct Change {
+    pub at: usize,
+    pub old: Vec<String>,
+    pub new: Vec<String>,
+}
+
+impl Change {
+    pub fn apply(&self, lines: &mut dyn Storage) {
+        lines.splice(self.at, self.old.len(), self.new.clone());
+    }
+
+    pub fn revert(&self, lines: &mut dyn Storage) {
+        lines.splice(self.at, self.new.len(), self.old.clone());
+    }
+}
+
+// Marks follow their lines when `n` lines at index `at` are replaced by `m`
+// others. The lines replaced one for one keep their marks, like a line
+// changed by s, and the marks of the others are removed with them.
+pub fn move_marks(marks: &mut BTreeMap<char, usize>, at: usize, n: usize, m: usize) {
+    marks.retain(|_, i| *i <= at + n.min(m) || *i > at + n);
+    for i in marks.values_mut() {
+        if *i > at + n {
+            *i = *i + m - n;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UndoState {
+    pub parent: Option<usize>,
+    pub child: Option<usize>, // Last visited child, followed by redo
+    pub inputs: Vec<String>, // Commands leading from the parent to this state
+    pub changes: Vec<Change>,
+    pub len: usize, // Number of commands leading from the root to this state
+    pub addr_before: usize,
+    pub addr_after: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct UndoTree {
+    pub states: Vec<UndoState>,
+    pub current: usize,
+    pub saved: Option<usize>,
+    pub undone: bool, // The last change was undone by `u`
+}
+
+impl UndoTree {
+    pub fn new(addr: usize) -> UndoTree {
+        let root = UndoState {
+            parent: None,
+            child: None,
+            inputs: Vec::new(),
+            changes: Vec::new(),
+            len: 0,
+            addr_before: addr,
+            addr_after: addr,
+        };
+        UndoTree {
+            states: vec![root],
+            current: 0,
+            saved: Some(0),
+            undone: false,
+        }
+    }
+
+    pub fn commit(&mut self, inputs: Vec<String>, changes: Vec<Change>, addr_before: usize, addr_after: usize) {
+        let i = self.states.len();
+        let len = self.states[self.current].len + inputs.len();
+        self.states.push(UndoState {
+            parent: Some(self.current),
+            child: None,
+            inputs,
+            changes,
+            len,
+            addr_before,
+            addr_after,
+        });
+        self.states[self.current].child = Some(i);
+        self.current = i;
+        self.undone = false;
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.states[self.current].len
+    }
+
+    pub fn parent(&self) -> Option<usize> {
+        self.states[self.current].parent
+    }
+
+    pub fn child(&self) -> Option<usize> {
+        self.states[self.current].child
+    }
+
+    pub fn is_saved(&self) -> bool {
+        self.saved == Some(self.current)
+    }
+
+    // Move to the given state, remembering the path taken to reach it so
+    // that redo will follow it again after an undo.
+    pub fn goto(&mut self, i: usize) {
+        let path = self.path(i);
+        for w in path.windows(2) {
+            self.states[w[0]].child = Some(w[1]);
+        }
+        self.current = i;
+    }
+
+    pub fn path(&self, i: usize) -> Vec<usize> {
+        let mut path = vec![i];
+        let mut j = i;
+        while let Some(parent) = self.states[j].parent {
+            path.push(parent);
+            j = parent;
+        }
+        path.reverse();
+        path
+    }
+
+    // Move the lines of the buffer from the current state to the given one,
+    // reverting the changes up to their closest common state and applying
+    // the changes down from there, with the marks following their lines.
+    pub fn goto_lines(&mut self, i: usize, lines: &mut dyn Storage, marks: &mut BTreeMap<char, usize>) {
+        let from = self.path(self.current);
+        let to = self.path(i);
+        let n = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+        for &j in from[n..].iter().rev() {
+            for change in self.states[j].changes.iter().rev() {
+                change.revert(lines);
+                move_marks(marks, change.at, change.new.len(), change.old.len());
+            }
+        }
+        for &j in &to[n..] {
+            for change in &self.states[j].changes {
+                change.apply(lines);
+                move_marks(marks, change.at, change.old.len(), change.new.len());
+            }
+        }
+        self.goto(i);
+    }
+
+    pub fn depth(&self, i: usize) -> usize {
+        self.path(i).len() - 1
+    }
+
+    pub fn inputs(&self, i: usize) -> Vec<String> {
+        self.path(i).iter().flat_map(|&j| self.states[j].inputs.clone()).collect()
+    }
+}
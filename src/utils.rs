@@ -1,45 +1,229 @@
 use crate::editor::Error;
-use colored::Colorize;
-use std::fs;
-
-static COLOR_ERRORS: &str = "red";
-static COLOR_NUMBERS: &str = "yellow";
-
-pub fn print_error(error: Error, show_help: bool) {
-    let msg = match error {
-        Error::InvalidCommand => "Invalid command",
-        Error::InvalidAddress => "Invalid address",
-        Error::NoFilename => "No file name",
-        Error::NoUndo => "Nothing to undo",
-        Error::CannotOpen => "Cannot open input file",
-        Error::Dirty => "No write since last change"
-    };
+use regex::{Regex, RegexBuilder};
+use crate::filesystem::Filesystem;
+use crate::format::{Encoding, Format};
+use crate::theme::Theme;
+use std::env;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+pub fn error_message(error: &Error) -> String {
+    match error {
+        Error::InvalidCommand => "Invalid command".to_string(),
+        Error::InvalidAddress => "Invalid address".to_string(),
+        Error::InvalidNumber => "Invalid number".to_string(),
+        Error::InvalidRegex => "Invalid regular expression".to_string(),
+        Error::NoMatch => "No match".to_string(),
+        Error::NoFilename => "No file name".to_string(),
+        Error::NoUndo => "Nothing to undo".to_string(),
+        Error::NoRedo => "Nothing to redo".to_string(),
+        Error::CannotOpen(kind) => format!("Cannot open input file: {}", kind),
+        Error::Io(kind) => format!("Input/output error: {}", kind),
+        Error::Dirty => "No write since last change".to_string(),
+        Error::Modified => "File changed on disk since last read or write".to_string(),
+        Error::Locked(pid) => format!("File locked by process {}", pid),
+        Error::ReadOnly => "Read-only buffer".to_string(),
+        Error::NoBuffer => "No such buffer".to_string(),
+        Error::EmptyRegister => "Empty register".to_string(),
+        Error::InvalidOption => "Invalid option".to_string(),
+        Error::Recoverable => "Unsaved changes from a previous session, recover them or discard them with e!".to_string(),
+        Error::NoJournal(kind) => format!("Cannot write the journal, changes will not be recoverable: {}", kind)
+    }
+}
+
+pub fn format_error(error: &Error, show_help: bool, theme: &Theme) -> String {
     if show_help {
-        println!("{}", format!("? {}", msg).bold().color(COLOR_ERRORS));
+        let msg = error_message(error);
+        theme.paint_error(&format!("? {}", msg))
     } else {
-        println!("{}", "?".bold().color(COLOR_ERRORS));
+        theme.paint_error("?")
     }
 }
 
-pub fn print_line(line: &str, i: usize, n: usize, show_number: bool) {
+pub fn format_line(line: &str, i: usize, n: usize, show_number: bool, theme: &Theme) -> String {
     if show_number {
         let width = n.to_string().len();
-        let number = format!("{:>width$}", i, width = width).color(COLOR_NUMBERS);
-        println!("{} {}", number, line);
+        let number = theme.paint(&format!("{:>width$}", i, width = width), theme.number);
+        format!("{} {}", number, line)
     } else {
-        println!("{}", line);
+        line.to_string()
     }
 }
 
-pub fn read_lines(path: &str) -> Result<Vec<String>, Error> {
-    match fs::read_to_string(path) {
+// Read the lines of a file in the given encoding, or in the one detected
+pub fn read_file<P: AsRef<Path>>(fs: &dyn Filesystem, path: P, encoding: Option<Encoding>) -> Result<(Vec<String>, Format), Error> {
+    match fs.read(path.as_ref()) {
         Err(e) => {
-            println!("{}", e);
-            Err(Error::CannotOpen)
+            Err(Error::CannotOpen(e.kind()))
         },
         Ok(data) => {
-            Ok(data.lines().map(|l| l.to_string()).collect())
+            let format = Format::detect(&data, encoding);
+            Ok((format.decode(&data), format))
+        }
+    }
+}
+
+pub fn read_lines<P: AsRef<Path>>(fs: &dyn Filesystem, path: P) -> Result<Vec<String>, Error> {
+    read_file(fs, path, Some(Encoding::Utf8)).map(|(lines, _)| lines)
+}
+
+pub fn build_regex(pattern: &str, case_insensitive: bool) -> Result<Regex, Error> {
+    RegexBuilder::new(pattern).
+        case_insensitive(case_insensitive).
+        multi_line(true).
+        build().map_err(|_| Error::InvalidRegex)
+}
+
+pub fn hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Diff {
+    Same(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+// Turn the lines of `a` into the ones of `b` with the fewest deletions and
+// insertions, found with the linear space variant of the algorithm of
+// Eugene Myers, which splits the lines around the middle of an edit path
+// until they only differ by insertions or deletions. Large differences are
+// given as a single replacement to bound the time taken.
+pub fn diff(a: &[String], b: &[String]) -> Vec<Diff> {
+    let mut res = Vec::new();
+    if a.len() + b.len() > 20000 {
+        let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+        let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev()).take_while(|(x, y)| x == y).count();
+        if a.len() + b.len() - 2 * (prefix + suffix) > 20000 {
+            res.extend((0..prefix).map(|i| Diff::Same(i, i)));
+            res.extend((prefix .. a.len() - suffix).map(Diff::Delete));
+            res.extend((prefix .. b.len() - suffix).map(Diff::Insert));
+            res.extend((0..suffix).map(|i| Diff::Same(a.len() - suffix + i, b.len() - suffix + i)));
+            return res;
         }
     }
+    let size = 2 * (a.len() + b.len()) + 4;
+    let (mut vf, mut vb) = (vec![0; size], vec![0; size]);
+    diff_range(a, b, (0, a.len()), (0, b.len()), &mut vf, &mut vb, &mut res);
+    res
+}
+
+fn diff_range(a: &[String], b: &[String], (x0, x1): (usize, usize), (y0, y1): (usize, usize), vf: &mut [usize], vb: &mut [usize], res: &mut Vec<Diff>) {
+    let prefix = a[x0..x1].iter().zip(&b[y0..y1]).take_while(|(x, y)| x == y).count();
+    res.extend((0..prefix).map(|i| Diff::Same(x0 + i, y0 + i)));
+    let (x0, y0) = (x0 + prefix, y0 + prefix);
+    let suffix = a[x0..x1].iter().rev().zip(b[y0..y1].iter().rev()).take_while(|(x, y)| x == y).count();
+    let (x1, y1) = (x1 - suffix, y1 - suffix);
+
+    if x0 == x1 || y0 == y1 {
+        res.extend((x0..x1).map(Diff::Delete));
+        res.extend((y0..y1).map(Diff::Insert));
+    } else {
+        let (x, y) = middle_snake(a, b, (x0, x1), (y0, y1), vf, vb);
+        diff_range(a, b, (x0, x), (y0, y), vf, vb, res);
+        diff_range(a, b, (x, x1), (y, y1), vf, vb, res);
+    }
+    res.extend((0..suffix).map(|i| Diff::Same(x1 + i, y1 + i)));
+}
+
+// The start of the snake crossed by the forward and backward searches of
+// the shortest edit path between two ranges that share no first or last
+// line, with `vf` and `vb` holding the furthest x reached on each diagonal.
+fn middle_snake(a: &[String], b: &[String], (x0, x1): (usize, usize), (y0, y1): (usize, usize), vf: &mut [usize], vb: &mut [usize]) -> (usize, usize) {
+    let (n, m) = ((x1 - x0) as isize, (y1 - y0) as isize);
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let offset = (n + m + 1) as usize;
+    let index = |k: isize| (k + offset as isize) as usize;
+    vf[index(1)] = 0;
+    vb[index(1)] = 0;
+    for d in 0..=(n + m + 1) / 2 {
+        for k in (-d..=d).rev().step_by(2) {
+            let start = if k == -d || (k != d && vf[index(k - 1)] < vf[index(k + 1)]) {
+                vf[index(k + 1)]
+            } else {
+                vf[index(k - 1)] + 1
+            } as isize;
+            let (mut x, mut y) = (start, start - k);
+            while x < n && y < m && a[x0 + x as usize] == b[y0 + y as usize] {
+                x += 1;
+                y += 1;
+            }
+            vf[index(k)] = x as usize;
+            if odd && (k - delta).abs() < d && x + vb[index(delta - k)] as isize >= n {
+                return (x0 + start as usize, y0 + (start - k) as usize);
+            }
+        }
+        for k in (-d..=d).rev().step_by(2) {
+            let start = if k == -d || (k != d && vb[index(k - 1)] < vb[index(k + 1)]) {
+                vb[index(k + 1)]
+            } else {
+                vb[index(k - 1)] + 1
+            } as isize;
+            let (mut x, mut y) = (start, start - k);
+            while x < n && y < m && a[x1 - 1 - x as usize] == b[y1 - 1 - y as usize] {
+                x += 1;
+                y += 1;
+            }
+            vb[index(k)] = x as usize;
+            if !odd && (k - delta).abs() <= d && x + vf[index(delta - k)] as isize >= n {
+                return (x1 - x as usize, y1 - y as usize);
+            }
+        }
+    }
+    unreachable!("the searches meet before d reaches (n + m) / 2")
+}
+
+// Format a time as "YYYYMMDD-HHMMSS" in UTC
+pub fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Convert the days since the epoch to a date in the proleptic
+    // Gregorian calendar
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+// The directory where journals are kept can be set with NED_STATE_DIR and
+// defaults to the one given by the XDG Base Directory Specification.
+pub fn default_state_dir() -> PathBuf {
+    if let Ok(dir) = env::var("NED_STATE_DIR") {
+        return PathBuf::from(dir);
+    }
+    let dir = match env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".local/state")
+    };
+    dir.join("ned")
+}
+
+// The startup file is looked for in the directory given by the XDG Base
+// Directory Specification.
+pub fn default_config_file() -> PathBuf {
+    let dir = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".config")
+    };
+    dir.join("ned").join("nedrc")
+}
+
+// Turn a path into a valid filename that can be decoded back, by escaping
+// slashes and percent signs like in URLs.
+pub fn escape_path(path: &Path) -> String {
+    path.to_string_lossy().replace('%', "%25").replace('/', "%2F")
 }